@@ -1,131 +1,3998 @@
-use image::{GenericImageView, ImageBuffer, RgbaImage};
-use std::io::{self, Write};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use image::{ImageFormat, RgbaImage};
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-/// Embeds a secret message into the image using LSB steganography.
-fn hide_message(image_path: &str, output_path: &str, message: &str) -> Result<(), String> {
-    let img = image::open(image_path).map_err(|e| e.to_string())?;
-    let (width, height) = img.dimensions();
+const MAGIC: &[u8; 4] = b"STGO";
+const FORMAT_VERSION: u8 = 7;
+const FLAG_ENCRYPTED: u8 = 0x01;
+const FLAG_ADAPTIVE: u8 = 0x02;
+/// The high nibble of the flags holds the matrix embedding parameter k (0 = off).
+const FLAG_MATRIX_SHIFT: u8 = 4;
+/// Magic, version, flags, embedding mode, ECC parity bits, shard set ID,
+/// index, count and threshold, and a 32-bit body length.
+const HEADER_LEN: usize = 19;
+/// The header is always protected by interleaved Hamming(7,4).
+const HEADER_PARITY_BITS: u8 = 3;
+/// Name length, data length and SHA-256 added around the data by `encode_payload`.
+const PAYLOAD_OVERHEAD: usize = 2 + 4 + 32;
 
-    let mut img_buffer: RgbaImage = img.to_rgba8();
-    let message_bytes = message.as_bytes();
-    let total_bytes = (width * height * 3) as usize;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
 
-    if message_bytes.len() * 8 + 32 > total_bytes {
-        return Err("Message is too large to fit in the image.".to_string());
+/// A hidden file: its original name (empty for a plain text message) and contents.
+struct Payload {
+    filename: String,
+    data: Vec<u8>,
+}
+
+/// Settings chosen at embed time; everything extraction needs is recorded in the header.
+struct HideOptions<'a> {
+    password: Option<&'a str>,
+    mode: EmbedMode,
+    /// Parity bits per Hamming block protecting the body (2-8), or 0 for none.
+    ecc_parity_bits: u8,
+    /// Matrix embedding parameter k (2-7): k bits per 2^k - 1 cover bits. 0 for plain LSB.
+    matrix_k: u8,
+    /// Change differing bits by ±1 instead of replacing them. Needs 1 bit per channel.
+    lsb_matching: bool,
+    /// Fill the most textured units first instead of following the plain order.
+    adaptive: bool,
+    /// How bits are written into text covers.
+    text_encoding: TextEncoding,
+}
+
+/// Which piece of a split payload a cover holds. A lone cover is piece 0 of 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ShardInfo {
+    /// Random ID shared by all pieces of one payload.
+    set_id: u32,
+    index: u8,
+    count: u8,
+    /// Shares needed to rebuild an erasure-coded payload; 0 when the pieces
+    /// are plain consecutive chunks that are all required.
+    threshold: u8,
+}
+
+impl ShardInfo {
+    const SINGLE: ShardInfo = ShardInfo {
+        set_id: 0,
+        index: 0,
+        count: 1,
+        threshold: 0,
+    };
+}
+
+/// The fields stored in front of every body.
+struct Header {
+    flags: u8,
+    mode: EmbedMode,
+    ecc_parity_bits: u8,
+    shard: ShardInfo,
+    body_len: usize,
+}
+
+impl Header {
+    /// Magic, format version, flags, mode, ECC strength, shard and body length.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(FORMAT_VERSION);
+        header.push(self.flags);
+        header.push(self.mode.to_byte());
+        header.push(self.ecc_parity_bits);
+        header.extend_from_slice(&self.shard.set_id.to_be_bytes());
+        header.push(self.shard.index);
+        header.push(self.shard.count);
+        header.push(self.shard.threshold);
+        header.extend_from_slice(&(self.body_len as u32).to_be_bytes());
+        header
+    }
+
+    /// Validates a decoded header. The body length is checked against the
+    /// cover separately, by `check_body_fits`.
+    fn parse(header: &[u8]) -> Result<Self, String> {
+        if &header[0..4] != MAGIC {
+            return Err("No hidden data found (or wrong password).".to_string());
+        }
+        if header[4] != FORMAT_VERSION {
+            return Err(format!("Unsupported format version {}.", header[4]));
+        }
+        let header = Header {
+            flags: header[5],
+            mode: EmbedMode::from_byte(header[6])?,
+            ecc_parity_bits: header[7],
+            shard: ShardInfo {
+                set_id: u32::from_be_bytes(header[8..12].try_into().unwrap()),
+                index: header[12],
+                count: header[13],
+                threshold: header[14],
+            },
+            body_len: u32::from_be_bytes(header[15..19].try_into().unwrap()) as usize,
+        };
+        if header.ecc_parity_bits != 0 && !(2..=8).contains(&header.ecc_parity_bits) {
+            return Err("Corrupted header: invalid error-correction setting.".to_string());
+        }
+        if header.shard.index >= header.shard.count || header.shard.threshold > header.shard.count {
+            return Err("Corrupted header: invalid shard numbering.".to_string());
+        }
+        let matrix_k = header.matrix_k();
+        if matrix_k != 0 && !(2..=7).contains(&matrix_k) {
+            return Err("Corrupted header: invalid matrix embedding setting.".to_string());
+        }
+        Ok(header)
+    }
+
+    fn matrix_k(&self) -> u8 {
+        self.flags >> FLAG_MATRIX_SHIFT
+    }
+}
+
+/// A body read back from a cover, after error correction but before decryption.
+struct RawBody {
+    flags: u8,
+    shard: ShardInfo,
+    body: Vec<u8>,
+    report: EccReport,
+}
+
+/// Bit errors repaired by the Hamming code during extraction.
+#[derive(Debug, Default)]
+struct EccReport {
+    header_errors: usize,
+    body_errors: usize,
+}
+
+/// Which channels carry hidden bits and how many low bits of each are used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct EmbedMode {
+    bits_per_channel: u8,
+    /// Bit 0 = red, 1 = green, 2 = blue, 3 = alpha.
+    channel_mask: u8,
+}
+
+impl EmbedMode {
+    /// One LSB in each of R, G and B.
+    const DEFAULT: EmbedMode = EmbedMode {
+        bits_per_channel: 1,
+        channel_mask: 0b0111,
+    };
+
+    /// Builds a mode from a bit depth (1-4) and a channel list such as `"rgb"` or `"rgba"`.
+    fn new(bits_per_channel: u8, channels: &str) -> Result<Self, String> {
+        if !(1..=4).contains(&bits_per_channel) {
+            return Err("Bits per channel must be between 1 and 4.".to_string());
+        }
+        let mut channel_mask = 0u8;
+        for c in channels.chars() {
+            channel_mask |= match c.to_ascii_lowercase() {
+                'r' => 0b0001,
+                'g' => 0b0010,
+                'b' => 0b0100,
+                'a' => 0b1000,
+                _ => return Err(format!("Unknown channel '{}'; use r, g, b or a.", c)),
+            };
+        }
+        Self::from_byte(((bits_per_channel - 1) << 4) | channel_mask)
+    }
+
+    /// Decodes the mode byte stored in the header.
+    fn from_byte(byte: u8) -> Result<Self, String> {
+        let mode = EmbedMode {
+            bits_per_channel: (byte >> 4) + 1,
+            channel_mask: byte & 0x0F,
+        };
+        if !(1..=4).contains(&mode.bits_per_channel) {
+            return Err("Bits per channel must be between 1 and 4.".to_string());
+        }
+        if mode.channel_mask == 0 {
+            return Err("At least one channel must be selected.".to_string());
+        }
+        Ok(mode)
+    }
+
+    fn to_byte(self) -> u8 {
+        ((self.bits_per_channel - 1) << 4) | self.channel_mask
+    }
+
+    /// Selected lanes of a carrier unit that has `lane_count` lanes. For
+    /// images lanes are R, G, B, A; for audio they are the audio channels.
+    fn lanes(self, lane_count: usize) -> Vec<usize> {
+        (0..lane_count.min(4))
+            .filter(|c| self.channel_mask & (1 << c) != 0)
+            .collect()
+    }
+
+    /// Hidden bits stored in each carrier unit.
+    fn bits_per_unit(self, lane_count: usize) -> usize {
+        self.lanes(lane_count).len() * self.bits_per_channel as usize
+    }
+}
+
+/// A cover medium whose samples can carry hidden bits. The medium is split
+/// into units (pixels, audio frames) that each hold a few integer lanes.
+/// Carriers are shared across threads while texture maps are computed.
+trait Carrier: Sync {
+    /// Number of units that can be visited by the embedding order.
+    fn units(&self) -> usize;
+
+    /// Number of lanes in every unit.
+    fn lane_count(&self) -> usize;
+
+    fn get(&self, unit: usize, lane: usize) -> i32;
+
+    fn set(&mut self, unit: usize, lane: usize, value: i32);
+
+    /// Smallest and largest value a lane can hold.
+    fn value_range(&self) -> (i32, i32);
+
+    /// Local variance around a unit, summed over its lanes and scaled to an
+    /// integer, with the low `ignored_bits` of every value masked off so
+    /// that embedding cannot change it.
+    fn texture(&self, unit: usize, ignored_bits: u8) -> u64;
+
+    /// Writes the (possibly modified) cover to disk.
+    fn save(&self, path: &str) -> Result<(), String>;
+}
+
+/// An image decoded to RGBA8; each pixel is a unit with four lanes.
+struct ImageCarrier {
+    buffer: RgbaImage,
+    /// Whether the cover had an alpha channel; covers without one are saved
+    /// as RGB again unless the alpha lane was embedded into.
+    has_alpha: bool,
+}
+
+impl Carrier for ImageCarrier {
+    fn units(&self) -> usize {
+        (self.buffer.width() as usize) * (self.buffer.height() as usize)
+    }
+
+    fn lane_count(&self) -> usize {
+        4
+    }
+
+    fn get(&self, unit: usize, lane: usize) -> i32 {
+        // Units are raster pixel indices, so the sample sits at a fixed offset.
+        self.buffer.as_raw()[unit * 4 + lane] as i32
+    }
+
+    fn set(&mut self, unit: usize, lane: usize, value: i32) {
+        let samples: &mut [u8] = &mut self.buffer;
+        samples[unit * 4 + lane] = value as u8;
+    }
+
+    fn value_range(&self) -> (i32, i32) {
+        (0, 255)
+    }
+
+    fn texture(&self, unit: usize, ignored_bits: u8) -> u64 {
+        let (width, height) = (self.buffer.width() as i64, self.buffer.height() as i64);
+        let (x, y) = (unit as i64 % width, unit as i64 / width);
+        let samples = self.buffer.as_raw();
+        let neighbours: Vec<usize> = (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            .filter(|&(nx, ny)| nx >= 0 && ny >= 0 && nx < width && ny < height)
+            .map(|(nx, ny)| (ny * width + nx) as usize * 4)
+            .collect();
+        (0..4)
+            .map(|lane| {
+                let values = neighbours
+                    .iter()
+                    .map(|&offset| (samples[offset + lane] >> ignored_bits) as u64);
+                scaled_variance(values)
+            })
+            .sum()
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        check_lossless_output(path)?;
+        if !self.has_alpha && self.buffer.pixels().all(|pixel| pixel[3] == 255) {
+            let rgb = image::DynamicImage::ImageRgba8(self.buffer.clone()).into_rgb8();
+            return rgb.save(path).map_err(|e| e.to_string());
+        }
+        self.buffer.save(path).map_err(|e| e.to_string())
+    }
+}
+
+/// Integer PCM audio; each frame is a unit with one lane per audio channel.
+struct WavCarrier {
+    spec: hound::WavSpec,
+    samples: Vec<i32>,
+}
+
+impl WavCarrier {
+    fn open(path: &str) -> Result<Self, String> {
+        let mut reader = hound::WavReader::open(path).map_err(|e| e.to_string())?;
+        let spec = reader.spec();
+        if spec.sample_format != hound::SampleFormat::Int {
+            return Err("Only integer PCM WAV files can carry hidden data.".to_string());
+        }
+        let samples = reader
+            .samples::<i32>()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(WavCarrier { spec, samples })
+    }
+}
+
+impl Carrier for WavCarrier {
+    fn units(&self) -> usize {
+        self.samples.len() / self.spec.channels as usize
+    }
+
+    fn lane_count(&self) -> usize {
+        self.spec.channels as usize
+    }
+
+    fn get(&self, unit: usize, lane: usize) -> i32 {
+        self.samples[unit * self.spec.channels as usize + lane]
+    }
+
+    fn set(&mut self, unit: usize, lane: usize, value: i32) {
+        self.samples[unit * self.spec.channels as usize + lane] = value;
+    }
+
+    fn value_range(&self) -> (i32, i32) {
+        let half = 1i64 << (self.spec.bits_per_sample - 1);
+        (-half as i32, (half - 1) as i32)
+    }
+
+    fn texture(&self, unit: usize, ignored_bits: u8) -> u64 {
+        let window = unit.saturating_sub(4)..(unit + 5).min(self.units());
+        (0..self.lane_count())
+            .map(|lane| {
+                let values = window.clone().map(|frame| {
+                    ((self.get(frame, lane) >> ignored_bits) as i64 - i32::MIN as i64) as u64
+                });
+                scaled_variance(values)
+            })
+            .sum()
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        if !is_wav_path(path) {
+            return Err("Audio carriers can only be saved as WAV.".to_string());
+        }
+        let mut writer = hound::WavWriter::create(path, self.spec).map_err(|e| e.to_string())?;
+        for &sample in &self.samples {
+            writer.write_sample(sample).map_err(|e| e.to_string())?;
+        }
+        writer.finalize().map_err(|e| e.to_string())
+    }
+}
+
+/// Where an indexed cover came from; GIFs keep their frame settings so the
+/// frame can be written back unchanged apart from its pixels and palette.
+enum PaletteSource {
+    Png,
+    Gif {
+        screen: (u16, u16),
+        frame: gif::Frame<'static>,
+    },
+}
+
+/// An indexed image embedded EzStego style: the palette is ordered by
+/// luminance and each pixel carries one bit, the parity of its entry's rank,
+/// so flipping a bit swaps the pixel to the neighbouring, similar colour.
+struct PaletteCarrier {
+    source: PaletteSource,
+    width: usize,
+    height: usize,
+    /// RGBA entries, padded to an even count so every entry has a partner.
+    palette: Vec<[u8; 4]>,
+    indices: Vec<u8>,
+    /// Luminance rank of each palette index.
+    rank: [u8; 256],
+    /// Palette index at each rank.
+    by_rank: Vec<u8>,
+    /// Pixels whose entry and partner entry share the same alpha; the pair
+    /// never changes during embedding, so both sides agree on this list.
+    usable: Vec<usize>,
+}
+
+impl PaletteCarrier {
+    fn open(path: &str) -> Result<Self, String> {
+        if is_gif_path(path) {
+            Self::open_gif(path)
+        } else {
+            Self::open_png(path)
+        }
+    }
+
+    fn open_png(path: &str) -> Result<Self, String> {
+        let mut decoder = png::Decoder::new(File::open(path).map_err(|e| e.to_string())?);
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+        let info = reader.info();
+        let rgb = info
+            .palette
+            .as_ref()
+            .ok_or_else(|| "The PNG has no palette.".to_string())?;
+        let alpha = info.trns.as_deref().unwrap_or(&[]);
+        let palette = rgb
+            .chunks_exact(3)
+            .enumerate()
+            .map(|(i, c)| [c[0], c[1], c[2], alpha.get(i).copied().unwrap_or(255)])
+            .collect();
+
+        // Unpack 1, 2 and 4 bit indices; they are written back as 8 bit.
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        let depth = frame.bit_depth as usize;
+        let mut indices = Vec::with_capacity(width * height);
+        for row in buffer.chunks(frame.line_size).take(height) {
+            indices.extend((0..width).map(|x| {
+                let bit = x * depth;
+                (row[bit / 8] >> (8 - depth - bit % 8)) & ((1u16 << depth) - 1) as u8
+            }));
+        }
+        Ok(Self::new(
+            PaletteSource::Png,
+            width,
+            height,
+            palette,
+            indices,
+        ))
+    }
+
+    fn open_gif(path: &str) -> Result<Self, String> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options
+            .read_info(File::open(path).map_err(|e| e.to_string())?)
+            .map_err(|e| e.to_string())?;
+        let screen = (decoder.width(), decoder.height());
+        let global_palette = decoder.global_palette().map(<[u8]>::to_vec);
+        let frame = decoder
+            .read_next_frame()
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| "The GIF has no frames.".to_string())?
+            .clone();
+        if decoder
+            .read_next_frame()
+            .map_err(|e| e.to_string())?
+            .is_some()
+        {
+            return Err("Animated GIFs are not supported.".to_string());
+        }
+        let rgb = frame
+            .palette
+            .clone()
+            .or(global_palette)
+            .ok_or_else(|| "The GIF has no palette.".to_string())?;
+        let palette = rgb
+            .chunks_exact(3)
+            .enumerate()
+            .map(|(i, c)| {
+                let alpha = if frame.transparent == Some(i as u8) {
+                    0
+                } else {
+                    255
+                };
+                [c[0], c[1], c[2], alpha]
+            })
+            .collect();
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        let indices = frame.buffer.to_vec();
+        Ok(Self::new(
+            PaletteSource::Gif { screen, frame },
+            width,
+            height,
+            palette,
+            indices,
+        ))
+    }
+
+    fn new(
+        source: PaletteSource,
+        width: usize,
+        height: usize,
+        mut palette: Vec<[u8; 4]>,
+        indices: Vec<u8>,
+    ) -> Self {
+        let luminance = |c: &[u8; 4]| 299 * c[0] as u32 + 587 * c[1] as u32 + 114 * c[2] as u32;
+        let mut by_rank: Vec<u8> = (0..palette.len()).map(|i| i as u8).collect();
+        by_rank.sort_by_key(|&i| luminance(&palette[i as usize]));
+        if palette.len() % 2 == 1 {
+            // Give the brightest entry an opaque twin; a full palette is even.
+            let [r, g, b, _] = palette[*by_rank.last().unwrap() as usize];
+            by_rank.push(palette.len() as u8);
+            palette.push([r, g, b, 255]);
+        }
+        let mut rank = [0u8; 256];
+        for (r, &index) in by_rank.iter().enumerate() {
+            rank[index as usize] = r as u8;
+        }
+        let usable = (0..width * height)
+            .filter(|&pixel| {
+                let index = indices[pixel] as usize;
+                if index >= palette.len() {
+                    return false;
+                }
+                let pair = (rank[index] & !1) as usize;
+                palette[by_rank[pair] as usize][3] == palette[by_rank[pair + 1] as usize][3]
+            })
+            .collect();
+        PaletteCarrier {
+            source,
+            width,
+            height,
+            palette,
+            indices,
+            rank,
+            by_rank,
+            usable,
+        }
+    }
+}
+
+impl Carrier for PaletteCarrier {
+    fn units(&self) -> usize {
+        self.usable.len()
+    }
+
+    fn lane_count(&self) -> usize {
+        1
+    }
+
+    fn get(&self, unit: usize, _lane: usize) -> i32 {
+        (self.rank[self.indices[self.usable[unit]] as usize] & 1) as i32
+    }
+
+    fn set(&mut self, unit: usize, _lane: usize, value: i32) {
+        let pixel = self.usable[unit];
+        let rank = (self.rank[self.indices[pixel] as usize] & !1) | value as u8;
+        self.indices[pixel] = self.by_rank[rank as usize];
+    }
+
+    fn value_range(&self) -> (i32, i32) {
+        (0, 1)
+    }
+
+    fn texture(&self, unit: usize, _ignored_bits: u8) -> u64 {
+        // Rank pairs are what embedding leaves untouched.
+        let pixel = self.usable[unit];
+        let (width, height) = (self.width as i64, self.height as i64);
+        let (x, y) = (pixel as i64 % width, pixel as i64 / width);
+        let values = (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            .filter(|&(nx, ny)| nx >= 0 && ny >= 0 && nx < width && ny < height)
+            .map(|(nx, ny)| {
+                let index = self.indices[(ny * width + nx) as usize];
+                (self.rank[index as usize] >> 1) as u64
+            });
+        scaled_variance(values)
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        match &self.source {
+            PaletteSource::Png if !is_png_path(path) => {
+                return Err("Indexed PNG covers must be saved as PNG.".to_string())
+            }
+            PaletteSource::Gif { .. } if !is_gif_path(path) => {
+                return Err("GIF covers must be saved as GIF.".to_string())
+            }
+            _ => {}
+        }
+        let rgb: Vec<u8> = self
+            .palette
+            .iter()
+            .flat_map(|c| [c[0], c[1], c[2]])
+            .collect();
+        let file = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+        match &self.source {
+            PaletteSource::Png => {
+                let mut encoder = png::Encoder::new(file, self.width as u32, self.height as u32);
+                encoder.set_color(png::ColorType::Indexed);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_palette(rgb);
+                if self.palette.iter().any(|c| c[3] != 255) {
+                    encoder.set_trns(self.palette.iter().map(|c| c[3]).collect::<Vec<_>>());
+                }
+                let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+                writer
+                    .write_image_data(&self.indices)
+                    .map_err(|e| e.to_string())
+            }
+            PaletteSource::Gif { screen, frame } => {
+                let mut frame = frame.clone();
+                frame.palette = Some(rgb);
+                frame.buffer = Cow::Owned(self.indices.clone());
+                let mut encoder =
+                    gif::Encoder::new(file, screen.0, screen.1, &[]).map_err(|e| e.to_string())?;
+                encoder.write_frame(&frame).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// A canonical JPEG Huffman table, usable in both directions.
+struct HuffmanTable {
+    /// Code and length of each symbol; length 0 if the symbol has no code.
+    codes: [(u16, u8); 256],
+    /// Largest code of each length, or -1 if there is none.
+    max_code: [i32; 17],
+    /// Index into `symbols` of a code of each length, minus the code.
+    offset: [i32; 17],
+    symbols: Vec<u8>,
+}
+
+impl HuffmanTable {
+    fn new(counts: &[u8], symbols: &[u8]) -> Result<Self, String> {
+        let corrupted = || "Corrupted JPEG: invalid Huffman table.".to_string();
+        if counts.iter().map(|&n| n as usize).sum::<usize>() != symbols.len() {
+            return Err(corrupted());
+        }
+        let mut table = HuffmanTable {
+            codes: [(0, 0); 256],
+            max_code: [-1; 17],
+            offset: [0; 17],
+            symbols: symbols.to_vec(),
+        };
+        let (mut code, mut next) = (0i32, 0usize);
+        for length in 1..=16 {
+            table.offset[length] = next as i32 - code;
+            for _ in 0..counts[length - 1] {
+                table.codes[symbols[next] as usize] = (code as u16, length as u8);
+                code += 1;
+                next += 1;
+            }
+            if code > 1 << length {
+                return Err(corrupted());
+            }
+            if counts[length - 1] > 0 {
+                table.max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        Ok(table)
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8, String> {
+        let mut code = 0;
+        for length in 1..=16 {
+            code = (code << 1) | reader.bit() as i32;
+            if code <= self.max_code[length] {
+                return Ok(self.symbols[(self.offset[length] + code) as usize]);
+            }
+        }
+        Err("Corrupted JPEG: invalid Huffman code.".to_string())
+    }
+
+    fn encode(&self, writer: &mut BitWriter, symbol: u8) -> Result<(), String> {
+        match self.codes[symbol as usize] {
+            (_, 0) => Err("A coefficient is out of range for a baseline JPEG.".to_string()),
+            (code, length) => {
+                writer.put(code as u32, length);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Reads bits from entropy-coded data with the byte stuffing already
+/// removed. Past the end it returns 1s, like the padding JPEG uses.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> u8 {
+        let byte = self.data.get(self.position / 8).copied().unwrap_or(0xFF);
+        self.position += 1;
+        (byte >> (7 - (self.position - 1) % 8)) & 1
+    }
+
+    /// Reads a `size`-bit magnitude and sign-extends it (F.2.2.1 EXTEND).
+    fn value(&mut self, size: u8) -> Result<i32, String> {
+        if size > 15 {
+            return Err("Corrupted JPEG: coefficient size out of range.".to_string());
+        }
+        let raw = (0..size).fold(0, |acc, _| (acc << 1) | self.bit() as i32);
+        if size > 0 && raw < 1 << (size - 1) {
+            Ok(raw - (1 << size) + 1)
+        } else {
+            Ok(raw)
+        }
+    }
+}
+
+/// Writes entropy-coded data, stuffing a zero after every 0xFF byte.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u32,
+    pending_bits: u8,
+}
+
+impl BitWriter {
+    fn put(&mut self, bits: u32, length: u8) {
+        for i in (0..length).rev() {
+            self.pending = (self.pending << 1) | ((bits >> i) & 1);
+            self.pending_bits += 1;
+            if self.pending_bits == 8 {
+                let byte = self.pending as u8;
+                self.bytes.push(byte);
+                if byte == 0xFF {
+                    self.bytes.push(0);
+                }
+                self.pending = 0;
+                self.pending_bits = 0;
+            }
+        }
+    }
+
+    /// Writes a value in `size` bits: negative values as value - 1.
+    fn put_value(&mut self, value: i32, size: u8) {
+        let bits = if value < 0 { value - 1 } else { value };
+        self.put(bits as u32 & ((1 << size) - 1), size);
+    }
+
+    /// Pads the last byte with 1s.
+    fn finish(mut self) -> Vec<u8> {
+        while self.pending_bits != 0 {
+            self.put(1, 1);
+        }
+        self.bytes
+    }
+}
+
+/// Number of bits in the magnitude of `value`, the JPEG size category.
+fn size_category(value: i32) -> u8 {
+    (32 - value.unsigned_abs().leading_zeros()) as u8
+}
+
+/// One colour component of a JPEG frame.
+struct JpegComponent {
+    id: u8,
+    h: usize,
+    v: usize,
+    /// Blocks per row and rows of blocks that cover the image.
+    blocks_wide: usize,
+    blocks_high: usize,
+    /// Row stride of the block grid, padded to whole MCUs as interleaved
+    /// scans store it.
+    padded_wide: usize,
+    /// Index of the component's first coefficient in `JpegCarrier::coefficients`.
+    base: usize,
+}
+
+/// The components one scan codes and the tables it codes them with.
+struct JpegScan {
+    /// Component index, and DC and AC table indices into `JpegCarrier::tables`.
+    components: Vec<(usize, usize, usize)>,
+    restart_interval: usize,
+}
+
+/// A baseline JPEG embedded JSteg style in its quantized DCT coefficients,
+/// so the file never goes through lossy recompression. Each usable AC
+/// coefficient carries one bit, the LSB of its magnitude; magnitudes 0 and
+/// 1 are skipped because changing them would be visible and would move
+/// coefficients in or out of use. Pairs like 2/3 and -4/-5 never cross a
+/// size category, so every Huffman symbol stays the same: the file is
+/// written back with its own tables, scan layout and restart markers, and
+/// only the magnitude bits change.
+struct JpegCarrier {
+    /// The file with the entropy-coded data of every scan cut out: the
+    /// bytes before each scan, then the bytes after the last one.
+    segments: Vec<Vec<u8>>,
+    scans: Vec<JpegScan>,
+    tables: Vec<HuffmanTable>,
+    mcus_wide: usize,
+    mcus_high: usize,
+    components: Vec<JpegComponent>,
+    /// Quantized coefficients, 64 per block in zigzag order.
+    coefficients: Vec<i16>,
+    usable: Vec<usize>,
+}
+
+impl JpegCarrier {
+    fn open(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| e.to_string())?;
+        if !data.starts_with(&[0xFF, 0xD8]) {
+            return Err("Not a JPEG file.".to_string());
+        }
+        let corrupted = |what: &str| format!("Corrupted JPEG: {}.", what);
+        let mut carrier = JpegCarrier {
+            segments: Vec::new(),
+            scans: Vec::new(),
+            tables: Vec::new(),
+            mcus_wide: 0,
+            mcus_high: 0,
+            components: Vec::new(),
+            coefficients: Vec::new(),
+            usable: Vec::new(),
+        };
+        // Indices into `carrier.tables` of the tables each slot holds now;
+        // a DHT segment may redefine them between scans.
+        let mut dc_tables = [None; 4];
+        let mut ac_tables = [None; 4];
+        let mut restart_interval = 0;
+        let (mut position, mut segment_start) = (2, 0);
+        loop {
+            if data.get(position) != Some(&0xFF) {
+                return Err(corrupted("expected a marker"));
+            }
+            while data.get(position) == Some(&0xFF) {
+                position += 1;
+            }
+            let marker = *data
+                .get(position)
+                .ok_or_else(|| corrupted("missing end of image"))?;
+            position += 1;
+            match marker {
+                0xD9 => break,
+                0x01 | 0xD0..=0xD7 => continue,
+                _ => {}
+            }
+            let length = data
+                .get(position..position + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+                .filter(|&length| length >= 2 && position + length <= data.len())
+                .ok_or_else(|| corrupted("truncated segment"))?;
+            let segment = &data[position + 2..position + length];
+            position += length;
+            match marker {
+                0xC0 | 0xC1 => carrier.read_frame(segment)?,
+                0xC2 | 0xC3 | 0xC5..=0xCF => {
+                    return Err(
+                        "Only baseline JPEGs can carry hidden data; this one is progressive, lossless or arithmetic coded."
+                            .to_string(),
+                    )
+                }
+                0xC4 => {
+                    let mut rest = segment;
+                    while !rest.is_empty() {
+                        let counts = rest
+                            .get(1..17)
+                            .ok_or_else(|| corrupted("truncated Huffman table"))?;
+                        let total: usize = counts.iter().map(|&n| n as usize).sum();
+                        let symbols = rest
+                            .get(17..17 + total)
+                            .ok_or_else(|| corrupted("truncated Huffman table"))?;
+                        let id = (rest[0] & 0x0F) as usize;
+                        match (rest[0] >> 4, id) {
+                            (0, 0..=3) => dc_tables[id] = Some(carrier.tables.len()),
+                            (1, 0..=3) => ac_tables[id] = Some(carrier.tables.len()),
+                            _ => return Err(corrupted("invalid Huffman table id")),
+                        }
+                        carrier.tables.push(HuffmanTable::new(counts, symbols)?);
+                        rest = &rest[17 + total..];
+                    }
+                }
+                0xDD => {
+                    restart_interval = segment
+                        .get(..2)
+                        .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+                        .ok_or_else(|| corrupted("truncated restart interval"))?
+                }
+                0xDA => {
+                    carrier
+                        .segments
+                        .push(data[segment_start..position].to_vec());
+                    let scan = carrier.read_scan_header(segment, &dc_tables, &ac_tables)?;
+                    let scan = JpegScan {
+                        components: scan,
+                        restart_interval,
+                    };
+                    position = carrier.decode_scan(&data, position, &scan)?;
+                    carrier.scans.push(scan);
+                    segment_start = position;
+                }
+                _ => {}
+            }
+        }
+        if carrier.components.is_empty() || carrier.scans.is_empty() {
+            return Err(corrupted("no image data"));
+        }
+        carrier.segments.push(data[segment_start..].to_vec());
+
+        for component in &carrier.components {
+            for row in 0..component.blocks_high {
+                for column in 0..component.blocks_wide {
+                    let block = component.base + (row * component.padded_wide + column) * 64;
+                    carrier.usable.extend(
+                        (block + 1..block + 64)
+                            .filter(|&i| carrier.coefficients[i].unsigned_abs() > 1),
+                    );
+                }
+            }
+        }
+        Ok(carrier)
+    }
+
+    /// Parses a SOF0/SOF1 segment and allocates the coefficient grid.
+    fn read_frame(&mut self, segment: &[u8]) -> Result<(), String> {
+        let corrupted = || "Corrupted JPEG: invalid frame header.".to_string();
+        let count = *segment.get(5).ok_or_else(corrupted)? as usize;
+        if !(1..=4).contains(&count) || segment.len() < 6 + 3 * count || !self.components.is_empty()
+        {
+            return Err(corrupted());
+        }
+        if segment[0] != 8 {
+            return Err("Only 8-bit JPEGs can carry hidden data.".to_string());
+        }
+        let height = u16::from_be_bytes([segment[1], segment[2]]) as usize;
+        let width = u16::from_be_bytes([segment[3], segment[4]]) as usize;
+        if height == 0 || width == 0 {
+            return Err(
+                "JPEGs that give their height after the scan are not supported.".to_string(),
+            );
+        }
+        let sampling: Vec<(usize, usize)> = segment[6..6 + 3 * count]
+            .chunks(3)
+            .map(|c| ((c[1] >> 4) as usize, (c[1] & 0x0F) as usize))
+            .collect();
+        if sampling
+            .iter()
+            .any(|&(h, v)| !(1..=4).contains(&h) || !(1..=4).contains(&v))
+        {
+            return Err(corrupted());
+        }
+        let h_max = sampling.iter().map(|&(h, _)| h).max().unwrap();
+        let v_max = sampling.iter().map(|&(_, v)| v).max().unwrap();
+        self.mcus_wide = width.div_ceil(8 * h_max);
+        self.mcus_high = height.div_ceil(8 * v_max);
+        let mut base = 0;
+        for (c, &(h, v)) in segment[6..6 + 3 * count].chunks(3).zip(&sampling) {
+            let component = JpegComponent {
+                id: c[0],
+                h,
+                v,
+                blocks_wide: (width * h).div_ceil(h_max).div_ceil(8),
+                blocks_high: (height * v).div_ceil(v_max).div_ceil(8),
+                padded_wide: self.mcus_wide * h,
+                base,
+            };
+            base += self.mcus_wide * h * self.mcus_high * v * 64;
+            self.components.push(component);
+        }
+        self.coefficients = vec![0; base];
+        Ok(())
+    }
+
+    /// Parses an SOS segment into (component, DC table, AC table) triples.
+    fn read_scan_header(
+        &self,
+        header: &[u8],
+        dc_tables: &[Option<usize>; 4],
+        ac_tables: &[Option<usize>; 4],
+    ) -> Result<Vec<(usize, usize, usize)>, String> {
+        let corrupted = |what: &str| format!("Corrupted JPEG: {}.", what);
+        let count = *header
+            .first()
+            .ok_or_else(|| corrupted("invalid scan header"))? as usize;
+        if !(1..=4).contains(&count) || header.len() < 4 + 2 * count || self.components.is_empty() {
+            return Err(corrupted("invalid scan header"));
+        }
+        if header[1 + 2 * count..4 + 2 * count] != [0, 63, 0] {
+            return Err(
+                "Only baseline JPEGs can carry hidden data; this one is progressive.".to_string(),
+            );
+        }
+        header[1..1 + 2 * count]
+            .chunks(2)
+            .map(|c| {
+                let component = self
+                    .components
+                    .iter()
+                    .position(|component| component.id == c[0])
+                    .ok_or_else(|| corrupted("scan of an unknown component"))?;
+                match (
+                    dc_tables[(c[1] >> 4) as usize & 3],
+                    ac_tables[(c[1] & 0x0F) as usize & 3],
+                ) {
+                    (Some(dc), Some(ac)) => Ok((component, dc, ac)),
+                    _ => Err(corrupted("scan uses a missing Huffman table")),
+                }
+            })
+            .collect()
+    }
+
+    /// The blocks of a scan in coding order, as (MCU, scan component,
+    /// index of the block's first coefficient).
+    fn scan_blocks(&self, scan: &JpegScan) -> Vec<(usize, usize, usize)> {
+        let mut blocks = Vec::new();
+        if let [(index, _, _)] = scan.components[..] {
+            // A single-component scan covers just the image's blocks, one
+            // per MCU, in raster order.
+            let component = &self.components[index];
+            for row in 0..component.blocks_high {
+                for column in 0..component.blocks_wide {
+                    blocks.push((
+                        row * component.blocks_wide + column,
+                        0,
+                        component.base + (row * component.padded_wide + column) * 64,
+                    ));
+                }
+            }
+            return blocks;
+        }
+        for mcu in 0..self.mcus_wide * self.mcus_high {
+            let (mcu_row, mcu_column) = (mcu / self.mcus_wide, mcu % self.mcus_wide);
+            for (slot, &(index, _, _)) in scan.components.iter().enumerate() {
+                let component = &self.components[index];
+                for y in 0..component.v {
+                    for x in 0..component.h {
+                        let row = mcu_row * component.v + y;
+                        let column = mcu_column * component.h + x;
+                        blocks.push((
+                            mcu,
+                            slot,
+                            component.base + (row * component.padded_wide + column) * 64,
+                        ));
+                    }
+                }
+            }
+        }
+        blocks
+    }
+
+    /// Decodes a scan whose entropy-coded data starts at `position`, and
+    /// returns the position of the marker after it.
+    fn decode_scan(
+        &mut self,
+        data: &[u8],
+        mut position: usize,
+        scan: &JpegScan,
+    ) -> Result<usize, String> {
+        // Unstuff the entropy-coded data, split at restart markers.
+        let mut intervals = vec![Vec::new()];
+        while let Some(&byte) = data.get(position) {
+            if byte != 0xFF {
+                intervals.last_mut().unwrap().push(byte);
+                position += 1;
+                continue;
+            }
+            match data.get(position + 1) {
+                Some(0x00) => intervals.last_mut().unwrap().push(0xFF),
+                Some(0xD0..=0xD7) => intervals.push(Vec::new()),
+                Some(0xFF) => {}
+                _ => break,
+            }
+            position += if data.get(position + 1) == Some(&0xFF) {
+                1
+            } else {
+                2
+            };
+        }
+
+        let mut interval = 0;
+        let mut reader = BitReader {
+            data: &intervals[0],
+            position: 0,
+        };
+        let mut predictions = vec![0; scan.components.len()];
+        for (mcu, slot, start) in self.scan_blocks(scan) {
+            if scan.restart_interval > 0 && mcu / scan.restart_interval != interval {
+                interval = mcu / scan.restart_interval;
+                reader = BitReader {
+                    data: intervals.get(interval).map_or(&[], Vec::as_slice),
+                    position: 0,
+                };
+                predictions.fill(0);
+            }
+            let (_, dc, ac) = scan.components[slot];
+            decode_block(
+                &mut reader,
+                &self.tables[dc],
+                &self.tables[ac],
+                &mut predictions[slot],
+                &mut self.coefficients[start..start + 64],
+            )?;
+        }
+        Ok(position)
+    }
+
+    /// Writes the file back with every scan re-encoded from the coefficients.
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut out = Vec::new();
+        for (segment, scan) in self.segments.iter().zip(&self.scans) {
+            out.extend(segment);
+            let mut writer = BitWriter::default();
+            let mut interval = 0;
+            let mut predictions = vec![0; scan.components.len()];
+            for (mcu, slot, start) in self.scan_blocks(scan) {
+                if scan.restart_interval > 0 && mcu / scan.restart_interval != interval {
+                    out.extend(std::mem::take(&mut writer).finish());
+                    out.extend([0xFF, 0xD0 + (interval % 8) as u8]);
+                    interval = mcu / scan.restart_interval;
+                    predictions.fill(0);
+                }
+                let (_, dc, ac) = scan.components[slot];
+                encode_block(
+                    &mut writer,
+                    &self.tables[dc],
+                    &self.tables[ac],
+                    &mut predictions[slot],
+                    &self.coefficients[start..start + 64],
+                )?;
+            }
+            out.extend(writer.finish());
+        }
+        out.extend(self.segments.last().unwrap());
+        Ok(out)
+    }
+}
+
+fn decode_block(
+    reader: &mut BitReader,
+    dc: &HuffmanTable,
+    ac: &HuffmanTable,
+    prediction: &mut i32,
+    block: &mut [i16],
+) -> Result<(), String> {
+    let size = dc.decode(reader)?;
+    *prediction += reader.value(size)?;
+    if !(-2048..=2047).contains(prediction) {
+        return Err("Corrupted JPEG: DC coefficient out of range.".to_string());
+    }
+    block[0] = *prediction as i16;
+    let mut k = 1;
+    while k < 64 {
+        let symbol = ac.decode(reader)?;
+        match (symbol >> 4, symbol & 0x0F) {
+            (15, 0) => k += 16,
+            (_, 0) => break,
+            (run, size) => {
+                k += run as usize;
+                if k > 63 {
+                    return Err("Corrupted JPEG: coefficient index out of range.".to_string());
+                }
+                block[k] = reader.value(size)? as i16;
+                k += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn encode_block(
+    writer: &mut BitWriter,
+    dc: &HuffmanTable,
+    ac: &HuffmanTable,
+    prediction: &mut i32,
+    block: &[i16],
+) -> Result<(), String> {
+    let difference = block[0] as i32 - *prediction;
+    *prediction = block[0] as i32;
+    let size = size_category(difference);
+    dc.encode(writer, size)?;
+    writer.put_value(difference, size);
+    let mut run = 0;
+    for &coefficient in &block[1..] {
+        if coefficient == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 15 {
+            ac.encode(writer, 0xF0)?;
+            run -= 16;
+        }
+        let size = size_category(coefficient as i32);
+        ac.encode(writer, (run << 4) | size)?;
+        writer.put_value(coefficient as i32, size);
+        run = 0;
+    }
+    if run > 0 {
+        ac.encode(writer, 0x00)?;
+    }
+    Ok(())
+}
+
+impl Carrier for JpegCarrier {
+    fn units(&self) -> usize {
+        self.usable.len()
+    }
+
+    fn lane_count(&self) -> usize {
+        1
+    }
+
+    fn get(&self, unit: usize, _lane: usize) -> i32 {
+        (self.coefficients[self.usable[unit]].unsigned_abs() & 1) as i32
+    }
+
+    fn set(&mut self, unit: usize, _lane: usize, value: i32) {
+        let coefficient = &mut self.coefficients[self.usable[unit]];
+        let magnitude = (coefficient.unsigned_abs() & !1) as i16 | value as i16;
+        *coefficient = magnitude * coefficient.signum();
+    }
+
+    fn value_range(&self) -> (i32, i32) {
+        (0, 1)
+    }
+
+    fn texture(&self, unit: usize, _ignored_bits: u8) -> u64 {
+        // Larger coefficients sit in busier blocks; embedding only moves the LSB.
+        (self.coefficients[self.usable[unit]].unsigned_abs() >> 1) as u64
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        if !is_jpeg_path(path) {
+            return Err("JPEG covers must be saved as JPEG.".to_string());
+        }
+        fs::write(path, self.encode()?).map_err(|e| e.to_string())
+    }
+}
+
+/// n² times the variance of the values, computed exactly as n·Σx² − (Σx)².
+fn scaled_variance(values: impl Iterator<Item = u64>) -> u64 {
+    let (mut n, mut sum, mut sum_sq) = (0u128, 0u128, 0u128);
+    for value in values {
+        n += 1;
+        sum += value as u128;
+        sum_sq += (value as u128) * (value as u128);
+    }
+    (n * sum_sq - sum * sum).min(u64::MAX as u128) as u64
+}
+
+/// How a text carrier stores its bits.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum TextEncoding {
+    /// Zero-width non-joiner (0) and joiner (1) characters at the end of every word.
+    ZeroWidth,
+    /// Spaces (0) and tabs (1) at the end of every line.
+    Whitespace,
+}
+
+const ZERO_WIDTH_NON_JOINER: char = '\u{200C}';
+const ZERO_WIDTH_JOINER: char = '\u{200D}';
+
+/// Bits stored at every word end or line end of a text cover.
+const TEXT_BITS_PER_GAP: usize = 8;
+
+/// Reads a run of zero-width non-joiners (0) and joiners (1) as bits.
+fn zero_width_bits(run: &str) -> Vec<u8> {
+    run.chars()
+        .map(|c| (c == ZERO_WIDTH_JOINER) as u8)
+        .collect()
+}
+
+/// Plain text whose word or line ends each carry `TEXT_BITS_PER_GAP` one-bit
+/// units. The text is kept as the segments between those gaps.
+struct TextCarrier {
+    encoding: TextEncoding,
+    segments: Vec<String>,
+    bits: Vec<u8>,
+}
+
+impl TextCarrier {
+    /// Splits the text at its gaps and reads whatever bits they hold. Without
+    /// an explicit encoding, a word ending in zero-width characters selects
+    /// `ZeroWidth` and anything else `Whitespace`; zero-width characters
+    /// inside words, as in emoji sequences, say nothing either way.
+    fn open(path: &str, encoding: Option<TextEncoding>) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let zero_width = [ZERO_WIDTH_NON_JOINER, ZERO_WIDTH_JOINER];
+        let encoding = encoding.unwrap_or(
+            if text.split(char::is_whitespace).any(|word| {
+                word.ends_with(zero_width) && !word.trim_end_matches(zero_width).is_empty()
+            }) {
+                TextEncoding::ZeroWidth
+            } else {
+                TextEncoding::Whitespace
+            },
+        );
+        let mut carrier = TextCarrier {
+            encoding,
+            segments: Vec::new(),
+            bits: Vec::new(),
+        };
+        match encoding {
+            TextEncoding::ZeroWidth => carrier.split_words(&text),
+            TextEncoding::Whitespace => carrier.split_lines(&text),
+        }
+        Ok(carrier)
+    }
+
+    /// Records a gap holding `run`, keeping its last `TEXT_BITS_PER_GAP` bits.
+    fn push_gap(&mut self, segment: String, run: &[u8]) {
+        self.segments.push(segment);
+        let start = run.len().saturating_sub(TEXT_BITS_PER_GAP);
+        let missing = TEXT_BITS_PER_GAP - (run.len() - start);
+        self.bits.resize(self.bits.len() + missing, 0);
+        self.bits.extend_from_slice(&run[start..]);
+    }
+
+    /// A gap sits wherever a word is followed by whitespace or the end of
+    /// the text. Zero-width characters anywhere else belong to the text, as
+    /// in emoji sequences and Persian words, and are kept as they are.
+    fn split_words(&mut self, text: &str) {
+        let ends_word = |segment: &str| segment.chars().last().is_some_and(|c| !c.is_whitespace());
+        let mut segment = String::new();
+        // Zero-width characters since the last other character.
+        let mut run = String::new();
+        for c in text.chars() {
+            if matches!(c, ZERO_WIDTH_NON_JOINER | ZERO_WIDTH_JOINER) {
+                run.push(c);
+                continue;
+            }
+            if c.is_whitespace() && ends_word(&segment) {
+                self.push_gap(std::mem::take(&mut segment), &zero_width_bits(&run));
+            } else {
+                segment.push_str(&run);
+            }
+            run.clear();
+            segment.push(c);
+        }
+        if ends_word(&segment) {
+            self.push_gap(std::mem::take(&mut segment), &zero_width_bits(&run));
+        } else {
+            segment.push_str(&run);
+        }
+        self.segments.push(segment);
+    }
+
+    /// A gap sits at the end of every line except an empty final one.
+    /// Existing trailing whitespace is read as bits and removed.
+    fn split_lines(&mut self, text: &str) {
+        let lines: Vec<&str> = text.split('\n').collect();
+        let mut segment = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                segment.push('\n');
+            }
+            let (line, line_end) = match line.strip_suffix('\r') {
+                Some(line) => (line, "\r"),
+                None => (*line, ""),
+            };
+            let content = line.trim_end_matches([' ', '\t']);
+            segment.push_str(content);
+            if i + 1 < lines.len() || !line.is_empty() {
+                let run: Vec<u8> = line[content.len()..]
+                    .chars()
+                    .map(|c| (c == '\t') as u8)
+                    .collect();
+                self.push_gap(std::mem::take(&mut segment), &run);
+            }
+            segment.push_str(line_end);
+        }
+        self.segments.push(segment);
+    }
+
+    /// The text with every gap left empty.
+    fn clean_text(&self) -> String {
+        self.segments.concat()
+    }
+
+    fn render(&self) -> String {
+        let (zero, one) = match self.encoding {
+            TextEncoding::ZeroWidth => (ZERO_WIDTH_NON_JOINER, ZERO_WIDTH_JOINER),
+            TextEncoding::Whitespace => (' ', '\t'),
+        };
+        let mut text = String::new();
+        for (segment, gap) in self
+            .segments
+            .iter()
+            .zip(self.bits.chunks(TEXT_BITS_PER_GAP))
+        {
+            text.push_str(segment);
+            text.extend(gap.iter().map(|&bit| if bit == 0 { zero } else { one }));
+        }
+        text.push_str(self.segments.last().map_or("", String::as_str));
+        text
+    }
+}
+
+impl Carrier for TextCarrier {
+    fn units(&self) -> usize {
+        self.bits.len()
+    }
+
+    fn lane_count(&self) -> usize {
+        1
+    }
+
+    fn get(&self, unit: usize, _lane: usize) -> i32 {
+        self.bits[unit] as i32
+    }
+
+    fn set(&mut self, unit: usize, _lane: usize, value: i32) {
+        self.bits[unit] = value as u8;
+    }
+
+    fn value_range(&self) -> (i32, i32) {
+        (0, 1)
+    }
+
+    fn texture(&self, _unit: usize, _ignored_bits: u8) -> u64 {
+        // Every gap is equally invisible.
+        0
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        if !is_text_path(path) {
+            return Err("Text carriers can only be saved as .txt files.".to_string());
+        }
+        fs::write(path, self.render()).map_err(|e| e.to_string())
+    }
+}
+
+fn is_wav_path(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"))
+}
+
+fn is_png_path(path: &str) -> bool {
+    matches!(ImageFormat::from_path(path), Ok(ImageFormat::Png))
+}
+
+fn is_jpeg_path(path: &str) -> bool {
+    matches!(ImageFormat::from_path(path), Ok(ImageFormat::Jpeg))
+}
+
+fn is_gif_path(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"))
+}
+
+/// Whether the file is a PNG stored with a palette.
+fn is_indexed_png(path: &str) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    png::Decoder::new(file)
+        .read_info()
+        .is_ok_and(|reader| reader.info().color_type == png::ColorType::Indexed)
+}
+
+fn is_text_path(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("txt"))
+}
+
+/// Opens a cover file as a WAV, text or image carrier based on its extension.
+/// Text covers use the given encoding, or the one detected in the text. GIFs
+/// and indexed PNGs stay indexed.
+fn open_carrier(
+    path: &str,
+    text_encoding: Option<TextEncoding>,
+) -> Result<Box<dyn Carrier>, String> {
+    if is_wav_path(path) {
+        Ok(Box::new(WavCarrier::open(path)?))
+    } else if is_text_path(path) {
+        Ok(Box::new(TextCarrier::open(path, text_encoding)?))
+    } else if is_gif_path(path) || is_indexed_png(path) {
+        Ok(Box::new(PaletteCarrier::open(path)?))
+    } else if is_jpeg_path(path) {
+        Ok(Box::new(JpegCarrier::open(path)?))
+    } else {
+        let img = image::open(path).map_err(|e| e.to_string())?;
+        Ok(Box::new(ImageCarrier {
+            has_alpha: img.color().has_alpha(),
+            buffer: img.to_rgba8(),
+        }))
+    }
+}
+
+/// The header is always stored in the default mode so it can be read before
+/// the mode is known; this many leading units of the order are reserved for it.
+fn header_units(lane_count: usize) -> usize {
+    let bits_per_unit = EmbedMode::DEFAULT.bits_per_unit(lane_count).max(1);
+    (ecc_coded_len(HEADER_LEN, HEADER_PARITY_BITS) * 8).div_ceil(bits_per_unit)
+}
+
+fn to_bits(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|&byte| (0..8).map(move |i| (byte >> (7 - i)) & 1))
+        .collect()
+}
+
+fn from_bits(bits: &[u8]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (i, &bit)| byte | (bit << (7 - i)))
+        })
+        .collect()
+}
+
+/// Block length and data bits of the Hamming code with `parity_bits` parity bits.
+fn hamming_shape(parity_bits: u8) -> (usize, usize) {
+    let n = (1usize << parity_bits) - 1;
+    (n, n - parity_bits as usize)
+}
+
+/// Length in bytes of `data_len` bytes after `ecc_encode`.
+fn ecc_coded_len(data_len: usize, parity_bits: u8) -> usize {
+    if parity_bits == 0 {
+        return data_len;
+    }
+    let (n, k) = hamming_shape(parity_bits);
+    ((data_len * 8).div_ceil(k) * n).div_ceil(8)
+}
+
+/// Encodes the data with a Hamming(2^r - 1, 2^r - 1 - r) code, r = `parity_bits`.
+/// Code bits are interleaved across blocks so that the several bits of one
+/// damaged pixel land in different blocks, where each can be corrected.
+fn ecc_encode(data: &[u8], parity_bits: u8) -> Vec<u8> {
+    if parity_bits == 0 {
+        return data.to_vec();
+    }
+    let (n, k) = hamming_shape(parity_bits);
+    let data_bits = to_bits(data);
+    let blocks = data_bits.len().div_ceil(k);
+
+    let mut stream = vec![0u8; blocks * n];
+    for block in 0..blocks {
+        // Positions are 1-based; powers of two hold parity, the rest hold data.
+        let mut code = vec![0u8; n + 1];
+        let mut next = block * k;
+        for (position, bit) in code.iter_mut().enumerate().skip(1) {
+            if !position.is_power_of_two() {
+                *bit = data_bits.get(next).copied().unwrap_or(0);
+                next += 1;
+            }
+        }
+        for p in 0..parity_bits {
+            let mask = 1 << p;
+            code[mask] = (1..=n)
+                .filter(|&i| i & mask != 0 && i != mask)
+                .fold(0, |acc, i| acc ^ code[i]);
+        }
+        for (j, &bit) in code[1..].iter().enumerate() {
+            stream[j * blocks + block] = bit;
+        }
+    }
+    from_bits(&stream)
+}
+
+/// Reverses `ecc_encode`, returning the data and the number of corrected bits.
+fn ecc_decode(coded: &[u8], parity_bits: u8, data_len: usize) -> (Vec<u8>, usize) {
+    if parity_bits == 0 {
+        return (coded[..data_len].to_vec(), 0);
+    }
+    let (n, k) = hamming_shape(parity_bits);
+    let stream = to_bits(coded);
+    let blocks = (data_len * 8).div_ceil(k);
+
+    let mut data_bits = Vec::with_capacity(blocks * k);
+    let mut corrected = 0;
+    for block in 0..blocks {
+        let mut code = vec![0u8; n + 1];
+        for (j, bit) in code[1..].iter_mut().enumerate() {
+            *bit = stream[j * blocks + block];
+        }
+        let syndrome = (1..=n).filter(|&i| code[i] == 1).fold(0, |acc, i| acc ^ i);
+        if syndrome != 0 {
+            code[syndrome] ^= 1;
+            corrected += 1;
+        }
+        data_bits.extend((1..=n).filter(|i| !i.is_power_of_two()).map(|i| code[i]));
+    }
+    data_bits.truncate(data_len * 8);
+    (from_bits(&data_bits), corrected)
+}
+
+/// Derives a 256-bit key from the passphrase with Argon2id.
+fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+/// Encrypts the payload with AES-256-GCM, returning `salt || nonce || ciphertext`.
+fn encrypt_payload(plaintext: &[u8], password: &str) -> Result<Vec<u8>, String> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let key = derive_key(password, &salt)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| "Failed to encrypt the message.".to_string())?;
+
+    let mut sealed = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Reverses `encrypt_payload`. A wrong passphrase fails the GCM tag check.
+fn decrypt_payload(sealed: &[u8], password: &str) -> Result<Vec<u8>, String> {
+    if sealed.len() < SALT_LEN + NONCE_LEN + TAG_LEN {
+        return Err("Hidden data is too short to be an encrypted message.".to_string());
+    }
+    let (salt, rest) = sealed.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let key = derive_key(password, salt)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Wrong password or corrupted data.".to_string())
+}
+
+/// Returns the order in which carrier units hold hidden bits. With a key the
+/// units are shuffled by a ChaCha20 stream seeded from it, so the bits are
+/// spread across the whole cover and only the key holder can find them.
+fn embedding_order(unit_count: usize, key: Option<&str>) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..unit_count).collect();
+    if let Some(key) = key {
+        let mut hasher = Sha256::new();
+        hasher.update(b"stegano-pixel-order");
+        hasher.update(key.as_bytes());
+        let mut rng = ChaCha20Rng::from_seed(hasher.finalize().into());
+        order.shuffle(&mut rng);
+    }
+    order
+}
+
+/// Reorders the body part of the embedding order so the most textured units
+/// come first (WOW/HUGO-style cost: changes in busy regions are hardest to
+/// see or model). The texture ignores the bits that carry data, so the
+/// extractor rebuilds the same order from the stego file. Ties keep the
+/// keyed order, which keeps the selection secret.
+fn adaptive_order(carrier: &dyn Carrier, body_order: &[usize], ignored_bits: u8) -> Vec<usize> {
+    let mut ranked: Vec<(u64, usize)> = body_order
+        .par_iter()
+        .map(|&unit| (carrier.texture(unit, ignored_bits), unit))
+        .collect();
+    ranked.par_sort_by_key(|&(texture, _)| std::cmp::Reverse(texture));
+    ranked.into_iter().map(|(_, unit)| unit).collect()
+}
+
+/// Maps the running index of a hidden bit to the unit, lane and bit plane
+/// that stores it, for one mode and region of the embedding order.
+struct Slots<'a> {
+    order: &'a [usize],
+    lanes: Vec<usize>,
+    bits_per_channel: usize,
+    bits_per_unit: usize,
+    first_unit: usize,
+    /// Use ±1 LSB matching instead of overwriting the bit.
+    lsb_matching: bool,
+}
+
+impl<'a> Slots<'a> {
+    fn new(carrier: &dyn Carrier, order: &'a [usize], mode: EmbedMode, first_unit: usize) -> Self {
+        Slots {
+            order,
+            lanes: mode.lanes(carrier.lane_count()),
+            bits_per_channel: mode.bits_per_channel as usize,
+            bits_per_unit: mode.bits_per_unit(carrier.lane_count()),
+            first_unit,
+            lsb_matching: false,
+        }
+    }
+
+    fn with_lsb_matching(mut self, lsb_matching: bool) -> Self {
+        self.lsb_matching = lsb_matching;
+        self
+    }
+
+    /// Number of bit slots from `first_unit` to the end of the order.
+    fn count(&self) -> usize {
+        self.order.len().saturating_sub(self.first_unit) * self.bits_per_unit
+    }
+
+    fn locate(&self, slot: usize) -> (usize, usize, usize) {
+        let unit = self.order[self.first_unit + slot / self.bits_per_unit];
+        let lane = self.lanes[(slot % self.bits_per_unit) / self.bits_per_channel];
+        let plane = self.bits_per_channel - 1 - slot % self.bits_per_channel;
+        (unit, lane, plane)
+    }
+
+    fn get(&self, carrier: &dyn Carrier, slot: usize) -> u8 {
+        let (unit, lane, plane) = self.locate(slot);
+        ((carrier.get(unit, lane) >> plane) & 1) as u8
+    }
+
+    /// Stores `bit` in the slot and reports whether the cover changed.
+    fn set(&self, carrier: &mut dyn Carrier, slot: usize, bit: u8) -> bool {
+        let (unit, lane, plane) = self.locate(slot);
+        let value = carrier.get(unit, lane);
+        match embed_bit(value, plane, bit, self.lsb_matching, carrier.value_range()) {
+            Some(updated) => {
+                carrier.set(unit, lane, updated);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The value with `bit` stored in bit `plane`, or `None` if it is already there.
+fn embed_bit(
+    value: i32,
+    plane: usize,
+    bit: u8,
+    lsb_matching: bool,
+    range: (i32, i32),
+) -> Option<i32> {
+    if ((value >> plane) & 1) as u8 == bit {
+        return None;
+    }
+    if !lsb_matching {
+        return Some((value & !(1 << plane)) | ((bit as i32) << plane));
+    }
+    // Randomly step up or down, which flips the LSB without the
+    // pairs-of-values artefact that the chi-square attack looks for.
+    let (min, max) = range;
+    let step = if rand::thread_rng().gen_bool(0.5) {
+        1
+    } else {
+        -1
+    };
+    // Compare before adding: 32-bit samples sit at the i32 limits.
+    if step > 0 && value == max || step < 0 && value == min {
+        Some(value - step)
+    } else {
+        Some(value + step)
+    }
+}
+
+/// How much of the cover an embedding touched.
+#[derive(Debug, Default)]
+struct EmbedStats {
+    message_bits: usize,
+    cover_bits_used: usize,
+    changes: usize,
+    /// Changes plain LSB replacement would have needed for the same bits.
+    naive_changes: usize,
+}
+
+impl EmbedStats {
+    fn add(&mut self, other: EmbedStats) {
+        self.message_bits += other.message_bits;
+        self.cover_bits_used += other.cover_bits_used;
+        self.changes += other.changes;
+        self.naive_changes += other.naive_changes;
+    }
+}
+
+/// Cover bits needed to hide `len` bytes with matrix parameter `matrix_k` (0 = off).
+fn slots_needed(len: usize, matrix_k: u8) -> usize {
+    if matrix_k == 0 {
+        return len * 8;
+    }
+    let n = (1usize << matrix_k) - 1;
+    (len * 8).div_ceil(matrix_k as usize) * n
+}
+
+/// Writes the bits of `bytes` into consecutive slots.
+fn write_bits(carrier: &mut dyn Carrier, slots: &Slots, bytes: &[u8]) -> EmbedStats {
+    let bits = to_bits(bytes);
+    let mut stats = EmbedStats {
+        message_bits: bits.len(),
+        cover_bits_used: bits.len(),
+        ..EmbedStats::default()
+    };
+    for (slot, &bit) in bits.iter().enumerate() {
+        if slots.set(carrier, slot, bit) {
+            stats.changes += 1;
+        }
+    }
+    stats.naive_changes = stats.changes;
+    stats
+}
+
+/// Reads `len` bytes back out of consecutive slots.
+fn read_bytes(carrier: &dyn Carrier, slots: &Slots, len: usize) -> Vec<u8> {
+    let bits: Vec<u8> = (0..len * 8).map(|slot| slots.get(carrier, slot)).collect();
+    from_bits(&bits)
+}
+
+/// F5-style matrix embedding: every block of n = 2^k - 1 slots carries k bits
+/// as the XOR of the (1-based) positions of its set bits, so at most one slot
+/// per block has to flip.
+fn write_bits_matrix(
+    carrier: &mut dyn Carrier,
+    slots: &Slots,
+    bytes: &[u8],
+    matrix_k: u8,
+) -> EmbedStats {
+    let k = matrix_k as usize;
+    let n = (1usize << k) - 1;
+    let bits = to_bits(bytes);
+    let mut stats = EmbedStats {
+        message_bits: bits.len(),
+        cover_bits_used: slots_needed(bytes.len(), matrix_k),
+        ..EmbedStats::default()
+    };
+
+    for (block, chunk) in bits.chunks(k).enumerate() {
+        let message =
+            chunk.iter().fold(0, |acc, &bit| (acc << 1) | bit as usize) << (k - chunk.len());
+        let syndrome = (0..n)
+            .filter(|&i| slots.get(carrier, block * n + i) == 1)
+            .fold(0, |acc, i| acc ^ (i + 1));
+        // What plain replacement of these bits would have changed.
+        stats.naive_changes += chunk
+            .iter()
+            .enumerate()
+            .filter(|&(i, &bit)| slots.get(carrier, block * n + i) != bit)
+            .count();
+
+        let position = syndrome ^ message;
+        if position != 0 {
+            let slot = block * n + position - 1;
+            let flipped = slots.get(carrier, slot) ^ 1;
+            slots.set(carrier, slot, flipped);
+            stats.changes += 1;
+        }
+    }
+    stats
+}
+
+/// Reverses `write_bits_matrix` by recomputing each block's syndrome.
+fn read_bytes_matrix(carrier: &dyn Carrier, slots: &Slots, len: usize, matrix_k: u8) -> Vec<u8> {
+    let k = matrix_k as usize;
+    let n = (1usize << k) - 1;
+    let mut bits = Vec::with_capacity(len * 8 + k);
+    for block in 0..(len * 8).div_ceil(k) {
+        let syndrome = (0..n)
+            .filter(|&i| slots.get(carrier, block * n + i) == 1)
+            .fold(0, |acc, i| acc ^ (i + 1));
+        bits.extend((0..k).rev().map(|i| ((syndrome >> i) & 1) as u8));
+    }
+    bits.truncate(len * 8);
+    from_bits(&bits)
+}
+
+/// Maximum number of body bytes the carrier can hold after the header.
+fn body_capacity(carrier: &dyn Carrier, mode: EmbedMode) -> usize {
+    carrier
+        .units()
+        .saturating_sub(header_units(carrier.lane_count()))
+        * mode.bits_per_unit(carrier.lane_count())
+        / 8
+}
+
+/// Largest unencrypted file (with an empty name) that fits in the carrier in the given mode.
+/// A file name costs its length in bytes and a password costs a further 44 bytes.
+fn capacity(carrier: &dyn Carrier, mode: EmbedMode) -> usize {
+    body_capacity(carrier, mode).saturating_sub(PAYLOAD_OVERHEAD)
+}
+
+/// Rejects output formats whose encoder would not preserve every channel bit.
+fn check_lossless_output(output_path: &str) -> Result<(), String> {
+    match ImageFormat::from_path(output_path).map_err(|e| e.to_string())? {
+        ImageFormat::Png
+        | ImageFormat::Bmp
+        | ImageFormat::Tiff
+        | ImageFormat::Tga
+        | ImageFormat::Pnm
+        | ImageFormat::Qoi => Ok(()),
+        ImageFormat::Jpeg => Err(
+            "JPEG compression would destroy the hidden bits; use a JPEG cover to embed in its DCT coefficients, or save as PNG."
+                .to_string(),
+        ),
+        format => Err(format!(
+            "{:?} output is lossy or palette-based and would destroy the hidden bits; save as PNG instead.",
+            format
+        )),
+    }
+}
+
+/// Serializes the payload as `name_len u16 | name | data_len u32 | sha256 | data`.
+fn encode_payload(payload: &Payload) -> Result<Vec<u8>, String> {
+    let name = payload.filename.as_bytes();
+    let name_len = u16::try_from(name.len()).map_err(|_| "File name is too long.".to_string())?;
+    let data_len =
+        u32::try_from(payload.data.len()).map_err(|_| "Payload is too large.".to_string())?;
+
+    let mut body = Vec::with_capacity(2 + name.len() + 4 + 32 + payload.data.len());
+    body.extend_from_slice(&name_len.to_be_bytes());
+    body.extend_from_slice(name);
+    body.extend_from_slice(&data_len.to_be_bytes());
+    body.extend_from_slice(&Sha256::digest(&payload.data));
+    body.extend_from_slice(&payload.data);
+    Ok(body)
+}
+
+/// Parses the output of `encode_payload` and verifies the checksum.
+fn decode_payload(body: &[u8]) -> Result<Payload, String> {
+    let truncated = || "Hidden payload is truncated.".to_string();
+
+    let name_len =
+        u16::from_be_bytes(body.get(0..2).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+    let rest = &body[2..];
+    let name = rest.get(..name_len).ok_or_else(truncated)?;
+    let rest = &rest[name_len..];
+    let data_len =
+        u32::from_be_bytes(rest.get(0..4).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+    let rest = &rest[4..];
+    let checksum = rest.get(..32).ok_or_else(truncated)?;
+    let data_end = data_len.checked_add(32).ok_or_else(truncated)?;
+    let data = rest.get(32..data_end).ok_or_else(truncated)?;
+
+    if Sha256::digest(data).as_slice() != checksum {
+        return Err("Checksum mismatch: the hidden payload is corrupted.".to_string());
+    }
+
+    Ok(Payload {
+        filename: String::from_utf8(name.to_vec()).map_err(|e| e.to_string())?,
+        data: data.to_vec(),
+    })
+}
+
+/// Serializes and, with a password, encrypts the payload. Returns the body
+/// and the header flags describing it.
+fn seal_payload(payload: &Payload, password: Option<&str>) -> Result<(Vec<u8>, u8), String> {
+    let body = encode_payload(payload)?;
+    match password {
+        Some(password) => Ok((encrypt_payload(&body, password)?, FLAG_ENCRYPTED)),
+        None => Ok((body, 0)),
+    }
+}
+
+/// Reverses `seal_payload`. There is no "password required" case: the
+/// password also seeds the embedding order, so without it the header of an
+/// encrypted payload is never found in the first place.
+fn open_payload(body: &[u8], flags: u8, password: Option<&str>) -> Result<Payload, String> {
+    match password.filter(|_| flags & FLAG_ENCRYPTED != 0) {
+        Some(password) => decode_payload(&decrypt_payload(body, password)?),
+        None => decode_payload(body),
+    }
+}
+
+/// Writes the header and an already sealed body into one cover.
+fn embed_body(
+    cover_path: &str,
+    output_path: &str,
+    body: &[u8],
+    mut flags: u8,
+    shard: ShardInfo,
+    options: &HideOptions,
+) -> Result<EmbedStats, String> {
+    let mode = options.mode;
+    if options.ecc_parity_bits != 0 && !(2..=8).contains(&options.ecc_parity_bits) {
+        return Err("Error-correction parity bits must be between 2 and 8.".to_string());
+    }
+    if options.matrix_k != 0 && !(2..=7).contains(&options.matrix_k) {
+        return Err("Matrix embedding k must be between 2 and 7.".to_string());
+    }
+    if options.lsb_matching && mode.bits_per_channel != 1 {
+        return Err("LSB matching only works with 1 bit per channel.".to_string());
+    }
+    if options.lsb_matching && options.adaptive {
+        // ±1 can carry into the higher bits the texture map is built from.
+        return Err("Adaptive embedding cannot be combined with LSB matching.".to_string());
+    }
+
+    flags |= options.matrix_k << FLAG_MATRIX_SHIFT;
+    if options.adaptive {
+        flags |= FLAG_ADAPTIVE;
+    }
+    let header = Header {
+        flags,
+        mode,
+        ecc_parity_bits: options.ecc_parity_bits,
+        shard,
+        body_len: body.len(),
+    };
+    let coded_header = ecc_encode(&header.to_bytes(), HEADER_PARITY_BITS);
+    let coded_body = ecc_encode(body, options.ecc_parity_bits);
+
+    // Plain raster order needs no random access, so suitable PNGs are
+    // processed one row at a time instead of being decoded whole.
+    if options.password.is_none() && !options.adaptive && options.matrix_k == 0 {
+        let rows = open_png_rows(cover_path).filter(|reader| {
+            reader.info().color_type == png::ColorType::Rgba || !mode.lanes(4).contains(&3)
+        });
+        if let Some(reader) = rows.filter(|_| is_png_path(output_path)) {
+            return stream_embed_png(
+                reader,
+                cover_path,
+                output_path,
+                &coded_header,
+                &coded_body,
+                mode,
+                options.lsb_matching,
+            );
+        }
+    }
+    embed_in_carrier(cover_path, output_path, &coded_header, &coded_body, options)
+}
+
+/// Rejects covers that cannot take the mode at all: samples too narrow for
+/// the bit depth, no selected channels, or too few units for the header.
+fn check_cover(carrier: &dyn Carrier, mode: EmbedMode, cover_path: &str) -> Result<(), String> {
+    let (low, high) = carrier.value_range();
+    if high as i64 - (low as i64) < (1i64 << mode.bits_per_channel) - 1 {
+        return Err(format!(
+            "{} cannot hold {} bits per channel.",
+            cover_path, mode.bits_per_channel
+        ));
+    }
+    if mode.bits_per_unit(carrier.lane_count()) == 0 {
+        return Err("None of the selected channels exist in this cover.".to_string());
+    }
+    if carrier.units() < header_units(carrier.lane_count()) {
+        return Err(format!("{} is too small to hold hidden data.", cover_path));
+    }
+    Ok(())
+}
+
+/// Longest sealed body `embed_body` can fit in the cover with these options,
+/// after error correction and matrix embedding take their share.
+fn max_body_len(cover_path: &str, options: &HideOptions) -> Result<usize, String> {
+    let carrier = open_carrier(cover_path, Some(options.text_encoding))?;
+    check_cover(carrier.as_ref(), options.mode, cover_path)?;
+    let lane_count = carrier.lane_count();
+    let slots =
+        (carrier.units() - header_units(lane_count)) * options.mode.bits_per_unit(lane_count);
+    let fits = |len: usize| {
+        slots_needed(
+            ecc_coded_len(len, options.ecc_parity_bits),
+            options.matrix_k,
+        ) <= slots
+    };
+    // Coding only ever adds bits, so `slots / 8` bounds the search.
+    let (mut low, mut high) = (0, slots / 8);
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(low)
+}
+
+/// Writes an ECC-coded header and body into a cover loaded whole.
+fn embed_in_carrier(
+    cover_path: &str,
+    output_path: &str,
+    coded_header: &[u8],
+    coded_body: &[u8],
+    options: &HideOptions,
+) -> Result<EmbedStats, String> {
+    let mode = options.mode;
+    let mut carrier = open_carrier(cover_path, Some(options.text_encoding))?;
+    check_cover(carrier.as_ref(), mode, cover_path)?;
+    let order = embedding_order(carrier.units(), options.password);
+
+    let first_unit = header_units(carrier.lane_count());
+    let body_order = if options.adaptive {
+        adaptive_order(
+            carrier.as_ref(),
+            &order[first_unit..],
+            mode.bits_per_channel,
+        )
+    } else {
+        order[first_unit..].to_vec()
+    };
+    let body_slots =
+        Slots::new(carrier.as_ref(), &body_order, mode, 0).with_lsb_matching(options.lsb_matching);
+    if slots_needed(coded_body.len(), options.matrix_k) > body_slots.count() {
+        return Err(format!("Message is too large to fit in {}.", cover_path));
+    }
+
+    let header_slots = Slots::new(carrier.as_ref(), &order, EmbedMode::DEFAULT, 0)
+        .with_lsb_matching(options.lsb_matching);
+    let mut stats = write_bits(carrier.as_mut(), &header_slots, coded_header);
+    stats.add(match options.matrix_k {
+        0 => write_bits(carrier.as_mut(), &body_slots, coded_body),
+        k => write_bits_matrix(carrier.as_mut(), &body_slots, coded_body, k),
+    });
+
+    carrier.save(output_path)?;
+    Ok(stats)
+}
+
+/// Opens an 8-bit, non-interlaced RGB or RGBA PNG for row-by-row access.
+/// Anything else goes through the in-memory carriers.
+fn open_png_rows(path: &str) -> Option<png::Reader<BufReader<File>>> {
+    if !is_png_path(path) {
+        return None;
+    }
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path).ok()?));
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let reader = decoder.read_info().ok()?;
+    let info = reader.info();
+    let streamable = info.bit_depth == png::BitDepth::Eight
+        && matches!(info.color_type, png::ColorType::Rgb | png::ColorType::Rgba)
+        && !info.interlaced
+        && info.animation_control.is_none();
+    streamable.then_some(reader)
+}
+
+/// The raster-order equivalent of `embed_in_carrier` for PNGs opened with
+/// `open_png_rows`: each row is decoded, embedded into and re-encoded before
+/// the next one is read, so memory use does not grow with the image.
+fn stream_embed_png(
+    reader: png::Reader<BufReader<File>>,
+    cover_path: &str,
+    output_path: &str,
+    coded_header: &[u8],
+    coded_body: &[u8],
+    mode: EmbedMode,
+    lsb_matching: bool,
+) -> Result<EmbedStats, String> {
+    let info = reader.info();
+    let units = info.width as usize * info.height as usize;
+    let body_slots = units.saturating_sub(header_units(4)) * mode.bits_per_unit(4);
+    if units < header_units(4) || coded_body.len() * 8 > body_slots {
+        return Err(format!("Message is too large to fit in {}.", cover_path));
+    }
+
+    // The output may be the cover itself, which is still being read, so
+    // the rows go to a file beside it that replaces it once complete.
+    let partial_path = format!("{}.partial", output_path);
+    let result = write_embedded_rows(
+        reader,
+        &partial_path,
+        coded_header,
+        coded_body,
+        mode,
+        lsb_matching,
+    )
+    .and_then(|stats| {
+        fs::rename(&partial_path, output_path)
+            .map(|()| stats)
+            .map_err(|e| e.to_string())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&partial_path);
+    }
+    result
+}
+
+/// Copies the rows of the cover to `path`, embedding the header and body.
+fn write_embedded_rows(
+    mut reader: png::Reader<BufReader<File>>,
+    path: &str,
+    coded_header: &[u8],
+    coded_body: &[u8],
+    mode: EmbedMode,
+    lsb_matching: bool,
+) -> Result<EmbedStats, String> {
+    let info = reader.info();
+    let (width, height) = (info.width as usize, info.height as usize);
+    let color_type = info.color_type;
+    let channels = color_type.samples();
+
+    let header_units = header_units(4);
+    let header_lanes = EmbedMode::DEFAULT.lanes(4);
+    let body_lanes = mode.lanes(4);
+    let bits_per_channel = mode.bits_per_channel as usize;
+
+    let file = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(color_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    let mut stream = writer.stream_writer().map_err(|e| e.to_string())?;
+
+    let header_bits = to_bits(coded_header);
+    let body_bits = to_bits(coded_body);
+    let mut stats = EmbedStats {
+        message_bits: header_bits.len() + body_bits.len(),
+        cover_bits_used: header_bits.len() + body_bits.len(),
+        ..EmbedStats::default()
+    };
+    let (mut header_bits, mut body_bits) = (header_bits.into_iter(), body_bits.into_iter());
+    let mut row = vec![0u8; width * channels];
+    for y in 0..height {
+        let decoded = reader
+            .next_row()
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| "The PNG ended early.".to_string())?;
+        row.copy_from_slice(decoded.data());
+        for x in 0..width {
+            let (bits, lanes, bits_per_channel) = if y * width + x < header_units {
+                (&mut header_bits, &header_lanes, 1)
+            } else {
+                (&mut body_bits, &body_lanes, bits_per_channel)
+            };
+            'lanes: for &lane in lanes {
+                for plane in (0..bits_per_channel).rev() {
+                    let Some(bit) = bits.next() else {
+                        break 'lanes;
+                    };
+                    let value = &mut row[x * channels + lane];
+                    if let Some(updated) =
+                        embed_bit(*value as i32, plane, bit, lsb_matching, (0, 255))
+                    {
+                        *value = updated as u8;
+                        stats.changes += 1;
+                    }
+                }
+            }
+        }
+        stream.write_all(&row).map_err(|e| e.to_string())?;
     }
+    stream.finish().map_err(|e| e.to_string())?;
+    stats.naive_changes = stats.changes;
+    Ok(stats)
+}
+
+/// Embeds a payload into an image or WAV cover using LSB steganography.
+/// When a password is given the payload is encrypted before embedding and
+/// the password also seeds the embedding order.
+fn hide_message(
+    cover_path: &str,
+    output_path: &str,
+    payload: &Payload,
+    options: &HideOptions,
+) -> Result<EmbedStats, String> {
+    let (body, flags) = seal_payload(payload, options.password)?;
+    embed_body(
+        cover_path,
+        output_path,
+        &body,
+        flags,
+        ShardInfo::SINGLE,
+        options,
+    )
+}
+
+/// Reads the header and body of one cover without decrypting it.
+fn read_body(stego_path: &str, password: Option<&str>) -> Result<RawBody, String> {
+    if password.is_none() {
+        if let Some(reader) = open_png_rows(stego_path) {
+            if let Some(raw) = stream_read_body(reader)? {
+                return Ok(raw);
+            }
+        }
+    }
+    read_body_in_carrier(stego_path, password)
+}
 
-    // Encode message length in the first 32 bits.
-    let message_length = message_bytes.len() as u32;
-    for i in 0..32 {
-        let bit = (message_length >> (31 - i)) & 1;
-        let pixel_index = i / 3;
-        let channel_index = i % 3;
+/// Checks that the coded body a header describes fits in `body_slots`.
+/// Never trust the stored length: a corrupted or non-stego header can claim
+/// far more data than the cover holds. The first, coarse bound keeps the
+/// exact size computations from overflowing.
+fn check_body_fits(header: &Header, body_slots: usize) -> Result<usize, String> {
+    let too_long =
+        || "Corrupted header: the stored length exceeds the cover's capacity.".to_string();
+    if header.body_len > body_slots / 8 {
+        return Err(too_long());
+    }
+    let coded_len = ecc_coded_len(header.body_len, header.ecc_parity_bits);
+    if slots_needed(coded_len, header.matrix_k()) > body_slots {
+        return Err(too_long());
+    }
+    Ok(coded_len)
+}
 
-        let x = (pixel_index as u32 % width) as u32;
-        let y = (pixel_index as u32 / width) as u32;
+/// Reads a body from a cover loaded whole. Text covers do not record
+/// which encoding hid their bits, so both are tried.
+fn read_body_in_carrier(stego_path: &str, password: Option<&str>) -> Result<RawBody, String> {
+    if is_text_path(stego_path) {
+        let read = |encoding| {
+            let carrier = TextCarrier::open(stego_path, Some(encoding))?;
+            read_body_from(&carrier, password)
+        };
+        return read(TextEncoding::ZeroWidth).or_else(|_| read(TextEncoding::Whitespace));
+    }
+    read_body_from(open_carrier(stego_path, None)?.as_ref(), password)
+}
 
-        let pixel = img_buffer.get_pixel_mut(x, y);
-        let color_channel = &mut pixel[channel_index as usize];  // Explicitly cast to usize
+/// Reads a body from a cover that is already open.
+fn read_body_from(carrier: &dyn Carrier, password: Option<&str>) -> Result<RawBody, String> {
+    let order = embedding_order(carrier.units(), password);
 
-        *color_channel = (*color_channel & 0xFE) | (bit as u8);
+    // Decode and validate the header.
+    let header_coded_len = ecc_coded_len(HEADER_LEN, HEADER_PARITY_BITS);
+    if carrier.lane_count() == 0 || carrier.units() < header_units(carrier.lane_count()) {
+        return Err("The file is too small to hold hidden data.".to_string());
     }
+    let header_slots = Slots::new(carrier, &order, EmbedMode::DEFAULT, 0);
+    let coded_header = read_bytes(carrier, &header_slots, header_coded_len);
+    let (header, header_errors) = ecc_decode(&coded_header, HEADER_PARITY_BITS, HEADER_LEN);
+    let header = Header::parse(&header)?;
+    let mode = header.mode;
 
-    // Encode the message in the least significant bits of the pixels.
-    for (i, &byte) in message_bytes.iter().enumerate() {
-        for bit_index in 0..8 {
-            let bit = (byte >> (7 - bit_index)) & 1;
-            let pixel_index = (32 + i * 8 + bit_index) / 3;
-            let channel_index = (32 + i * 8 + bit_index) % 3;
+    let body_order = &order[header_units(carrier.lane_count())..];
+    let body_order = if header.flags & FLAG_ADAPTIVE != 0 {
+        adaptive_order(carrier, body_order, mode.bits_per_channel)
+    } else {
+        body_order.to_vec()
+    };
+    let body_slots = Slots::new(carrier, &body_order, mode, 0);
+    if mode.bits_per_unit(carrier.lane_count()) == 0 {
+        return Err(
+            "Corrupted header: the stored length exceeds the cover's capacity.".to_string(),
+        );
+    }
+    let coded_len = check_body_fits(&header, body_slots.count())?;
+    let coded_body = match header.matrix_k() {
+        0 => read_bytes(carrier, &body_slots, coded_len),
+        k => read_bytes_matrix(carrier, &body_slots, coded_len, k),
+    };
+    let (body, body_errors) = ecc_decode(&coded_body, header.ecc_parity_bits, header.body_len);
 
-            let x = (pixel_index as u32 % width) as u32;
-            let y = (pixel_index as u32 / width) as u32;
+    Ok(RawBody {
+        flags: header.flags,
+        shard: header.shard,
+        body,
+        report: EccReport {
+            header_errors,
+            body_errors,
+        },
+    })
+}
 
-            let pixel = img_buffer.get_pixel_mut(x, y);
-            let color_channel = &mut pixel[channel_index as usize];  // Explicitly cast to usize
+/// Pixels of a PNG opened with `open_png_rows`, decoded one row at a time.
+/// RGB pixels read as fully opaque, like they do in `ImageCarrier`.
+struct PngPixels {
+    reader: png::Reader<BufReader<File>>,
+    channels: usize,
+    row: Vec<u8>,
+    offset: usize,
+}
 
-            *color_channel = (*color_channel & 0xFE) | (bit as u8);
+impl PngPixels {
+    fn next_pixel(&mut self) -> Result<Option<[u8; 4]>, String> {
+        if self.offset == self.row.len() {
+            match self.reader.next_row().map_err(|e| e.to_string())? {
+                Some(row) => self.row = row.data().to_vec(),
+                None => return Ok(None),
+            }
+            self.offset = 0;
         }
+        let mut pixel = [255; 4];
+        pixel[..self.channels].copy_from_slice(&self.row[self.offset..self.offset + self.channels]);
+        self.offset += self.channels;
+        Ok(Some(pixel))
     }
 
-    img_buffer.save(output_path).map_err(|e| e.to_string())?;
-    Ok(())
+    /// Appends the bits of the following pixels in the given lanes, highest
+    /// plane first, until `bits` holds `limit` entries.
+    fn read_bits(
+        &mut self,
+        bits: &mut Vec<u8>,
+        limit: usize,
+        lanes: &[usize],
+        bits_per_channel: usize,
+    ) -> Result<(), String> {
+        while bits.len() < limit {
+            let pixel = self
+                .next_pixel()?
+                .ok_or_else(|| "The PNG ended early.".to_string())?;
+            for &lane in lanes {
+                for plane in (0..bits_per_channel).rev() {
+                    bits.push((pixel[lane] >> plane) & 1);
+                }
+            }
+        }
+        bits.truncate(limit);
+        Ok(())
+    }
+}
+
+/// The raster-order equivalent of `read_body_in_carrier`. Returns `None`
+/// for adaptive and matrix embeddings, which need the whole cover.
+fn stream_read_body(reader: png::Reader<BufReader<File>>) -> Result<Option<RawBody>, String> {
+    let info = reader.info();
+    let units = info.width as usize * info.height as usize;
+    let channels = info.color_type.samples();
+    let header_units = header_units(4);
+    if units < header_units {
+        return Err("The file is too small to hold hidden data.".to_string());
+    }
+    let mut pixels = PngPixels {
+        reader,
+        channels,
+        row: Vec::new(),
+        offset: 0,
+    };
+
+    let header_lanes = EmbedMode::DEFAULT.lanes(4);
+    let header_coded_len = ecc_coded_len(HEADER_LEN, HEADER_PARITY_BITS);
+    let mut bits = Vec::with_capacity(header_units * header_lanes.len());
+    pixels.read_bits(
+        &mut bits,
+        header_units * header_lanes.len(),
+        &header_lanes,
+        1,
+    )?;
+    let (header, header_errors) = ecc_decode(
+        &from_bits(&bits[..header_coded_len * 8]),
+        HEADER_PARITY_BITS,
+        HEADER_LEN,
+    );
+    let header = Header::parse(&header)?;
+    if header.flags & FLAG_ADAPTIVE != 0 || header.matrix_k() != 0 {
+        return Ok(None);
+    }
+
+    let mode = header.mode;
+    let per_unit = mode.bits_per_unit(4);
+    if per_unit == 0 {
+        return Err(
+            "Corrupted header: the stored length exceeds the cover's capacity.".to_string(),
+        );
+    }
+    let coded_len = check_body_fits(&header, (units - header_units) * per_unit)?;
+    bits.clear();
+    let lanes = mode.lanes(4);
+    // Read whole units, then drop the padding bits of the last one.
+    pixels.read_bits(
+        &mut bits,
+        (coded_len * 8).div_ceil(per_unit) * per_unit,
+        &lanes,
+        mode.bits_per_channel as usize,
+    )?;
+    bits.truncate(coded_len * 8);
+    let (body, body_errors) =
+        ecc_decode(&from_bits(&bits), header.ecc_parity_bits, header.body_len);
+
+    Ok(Some(RawBody {
+        flags: header.flags,
+        shard: header.shard,
+        body,
+        report: EccReport {
+            header_errors,
+            body_errors,
+        },
+    }))
+}
+
+/// Retrieves a hidden payload from an image or WAV file using LSB steganography.
+/// The password must match the one used by `hide_message`, if any.
+fn retrieve_message(
+    stego_path: &str,
+    password: Option<&str>,
+) -> Result<(Payload, EccReport), String> {
+    let raw = read_body(stego_path, password)?;
+    if raw.shard.count != 1 {
+        return Err(format!(
+            "This file holds part {} of {}; extract from the directory holding all parts.",
+            raw.shard.index + 1,
+            raw.shard.count
+        ));
+    }
+    Ok((open_payload(&raw.body, raw.flags, password)?, raw.report))
+}
+
+/// Multiplication in GF(2^8) with the AES polynomial.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1B;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse in GF(2^8), as a^254.
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    for _ in 0..254 {
+        result = gf_mul(result, a);
+    }
+    result
+}
+
+/// Evaluates at `x`, column by column, the polynomials of degree below
+/// `points.len()` that pass through the `(x, row)` points (Lagrange
+/// interpolation in GF(2^8)).
+fn interpolate(points: &[(u8, &[u8])], x: u8) -> Vec<u8> {
+    let len = points.iter().map(|(_, row)| row.len()).min().unwrap_or(0);
+    let weights: Vec<u8> = points
+        .iter()
+        .map(|&(xi, _)| {
+            points
+                .iter()
+                .filter(|&&(xj, _)| xj != xi)
+                .fold(1, |acc, &(xj, _)| {
+                    gf_mul(acc, gf_mul(x ^ xj, gf_inv(xi ^ xj)))
+                })
+        })
+        .collect();
+    (0..len)
+        .map(|i| {
+            points
+                .iter()
+                .zip(&weights)
+                .fold(0, |acc, (&(_, row), &w)| acc ^ gf_mul(row[i], w))
+        })
+        .collect()
+}
+
+/// Reed-Solomon erasure coding: splits `data` into `threshold` equal shares
+/// and adds `count - threshold` parity shares, any `threshold` of which
+/// rebuild it. Share i is the column polynomials evaluated at x = i + 1, and
+/// the first `threshold` shares are the data itself. The data is prefixed
+/// with its length so the zero padding can be dropped again.
+fn erasure_split(data: &[u8], count: u8, threshold: u8) -> Vec<Vec<u8>> {
+    let mut framed = (data.len() as u32).to_be_bytes().to_vec();
+    framed.extend_from_slice(data);
+    let share_len = framed.len().div_ceil(threshold as usize);
+    framed.resize(share_len * threshold as usize, 0);
+
+    let mut shares: Vec<Vec<u8>> = framed.chunks(share_len).map(<[u8]>::to_vec).collect();
+    let parity: Vec<Vec<u8>> = {
+        let points: Vec<(u8, &[u8])> = shares
+            .iter()
+            .enumerate()
+            .map(|(i, share)| (i as u8 + 1, share.as_slice()))
+            .collect();
+        (threshold..count)
+            .map(|i| interpolate(&points, i + 1))
+            .collect()
+    };
+    shares.extend(parity);
+    shares
+}
+
+/// Rebuilds the data from `threshold` distinct `(x, share)` pairs.
+fn erasure_combine(shares: &[(u8, &[u8])], threshold: u8) -> Result<Vec<u8>, String> {
+    let mut framed = Vec::new();
+    for x in 1..=threshold {
+        match shares.iter().find(|&&(xi, _)| xi == x) {
+            Some((_, share)) => framed.extend_from_slice(share),
+            None => framed.extend(interpolate(shares, x)),
+        }
+    }
+    let corrupted = || "Corrupted shares: the stored length is invalid.".to_string();
+    let len =
+        u32::from_be_bytes(framed.get(..4).ok_or_else(corrupted)?.try_into().unwrap()) as usize;
+    if len > framed.len() - 4 {
+        return Err(corrupted());
+    }
+    Ok(framed[4..4 + len].to_vec())
+}
+
+/// Splits a payload across several covers, writing the stego files into
+/// `output_dir`. With a threshold k every cover gets an erasure-coded share
+/// about 1/k the size of the payload and any k of them are enough; otherwise
+/// each cover holds one consecutive chunk, sized to its capacity, and all are
+/// needed. Every cover is checked before anything is written, and a failed
+/// embed removes the parts already written.
+fn hide_split(
+    cover_paths: &[String],
+    output_dir: &str,
+    payload: &Payload,
+    options: &HideOptions,
+    threshold: u8,
+) -> Result<Vec<(PathBuf, EmbedStats)>, String> {
+    let count = u8::try_from(cover_paths.len())
+        .ok()
+        .filter(|&count| count >= 1)
+        .ok_or_else(|| "Between 1 and 255 covers are needed.".to_string())?;
+    if threshold != 0 && !(2..=count).contains(&threshold) {
+        return Err(format!("The threshold must be between 2 and {}.", count));
+    }
+
+    let (body, flags) = seal_payload(payload, options.password)?;
+    let capacities = cover_paths
+        .iter()
+        .map(|path| max_body_len(path, options))
+        .collect::<Result<Vec<usize>, String>>()?;
+    let pieces = if threshold == 0 {
+        let total: usize = capacities.iter().sum();
+        if body.len() > total {
+            return Err(format!(
+                "The payload needs {} bytes but the covers hold only {} together.",
+                body.len(),
+                total
+            ));
+        }
+        // Give each cover a share proportional to its capacity. Rounding the
+        // running boundary up never gives a cover more than it holds.
+        let mut chunks = Vec::with_capacity(capacities.len());
+        let (mut start, mut filled) = (0, 0);
+        for &cover_capacity in &capacities {
+            filled += cover_capacity;
+            let end = (body.len() as u128 * filled as u128).div_ceil(total as u128) as usize;
+            chunks.push(body[start..end].to_vec());
+            start = end;
+        }
+        chunks
+    } else {
+        let shares = erasure_split(&body, count, threshold);
+        let share_len = shares[0].len();
+        if let Some((path, cover_capacity)) = cover_paths
+            .iter()
+            .zip(&capacities)
+            .find(|&(_, &cover_capacity)| cover_capacity < share_len)
+        {
+            return Err(format!(
+                "{} holds {} bytes but each share needs {}.",
+                path, cover_capacity, share_len
+            ));
+        }
+        shares
+    };
+
+    fs::create_dir_all(output_dir).map_err(|e| e.to_string())?;
+    let set_id = rand::thread_rng().gen();
+    let mut results = Vec::with_capacity(pieces.len());
+    for (index, (cover_path, piece)) in cover_paths.iter().zip(&pieces).enumerate() {
+        let shard = ShardInfo {
+            set_id,
+            index: index as u8,
+            count,
+            threshold,
+        };
+        let stem = Path::new(cover_path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = if is_wav_path(cover_path) {
+            "wav"
+        } else if is_text_path(cover_path) {
+            "txt"
+        } else if is_gif_path(cover_path) {
+            "gif"
+        } else if is_jpeg_path(cover_path) {
+            "jpg"
+        } else {
+            "png"
+        };
+        let output_path =
+            Path::new(output_dir).join(format!("{:03}-{}.{}", index + 1, stem, extension));
+        let output = output_path.to_string_lossy();
+        match embed_body(cover_path, &output, piece, flags, shard, options) {
+            Ok(stats) => results.push((output_path, stats)),
+            Err(e) => {
+                // Leave no partial set behind; the files may not exist, so
+                // removal errors are ignored.
+                let _ = fs::remove_file(&output_path);
+                for (written, _) in &results {
+                    let _ = fs::remove_file(written);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Reassembles a payload split by `hide_split` from the files in a directory.
+/// Files that hold no readable shard are ignored.
+fn retrieve_split(dir: &str, password: Option<&str>) -> Result<(Payload, EccReport), String> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| e.to_string())?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    entries.sort();
+
+    let mut parts: Vec<RawBody> = entries
+        .iter()
+        .filter_map(|path| read_body(&path.to_string_lossy(), password).ok())
+        .collect();
+    let first = parts
+        .first()
+        .ok_or_else(|| "No hidden parts found (or wrong password).".to_string())?;
+    let (set_id, count, threshold, flags) = (
+        first.shard.set_id,
+        first.shard.count,
+        first.shard.threshold,
+        first.flags,
+    );
+    if parts.iter().any(|part| part.shard.set_id != set_id) {
+        return Err("The directory holds parts of more than one payload.".to_string());
+    }
+    parts.sort_by_key(|part| part.shard.index);
+    parts.dedup_by_key(|part| part.shard.index);
+
+    let body = if threshold == 0 {
+        let missing: Vec<String> = (0..count)
+            .filter(|&index| !parts.iter().any(|part| part.shard.index == index))
+            .map(|index| (index + 1).to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "Missing part(s) {} of {}.",
+                missing.join(", "),
+                count
+            ));
+        }
+        parts
+            .iter()
+            .flat_map(|part| part.body.iter().copied())
+            .collect()
+    } else {
+        if parts.len() < threshold as usize {
+            return Err(format!(
+                "Found {} shares but {} are needed to rebuild the payload.",
+                parts.len(),
+                threshold
+            ));
+        }
+        let shares: Vec<(u8, &[u8])> = parts
+            .iter()
+            .take(threshold as usize)
+            .map(|part| (part.shard.index + 1, part.body.as_slice()))
+            .collect();
+        erasure_combine(&shares, threshold)?
+    };
+
+    let report = EccReport {
+        header_errors: parts.iter().map(|part| part.report.header_errors).sum(),
+        body_errors: parts.iter().map(|part| part.report.body_errors).sum(),
+    };
+    Ok((open_payload(&body, flags, password)?, report))
+}
+
+/// Steganalysis results for one colour channel. Rates are estimated
+/// fractions of the channel's samples that carry hidden bits.
+struct ChannelReport {
+    /// Probability from the chi-square attack that the whole channel is embedded.
+    chi_square_p: f64,
+    /// Fraction of the channel (in raster order) over which the chi-square
+    /// attack still reports embedding; catches sequential embedding.
+    chi_square_rate: f64,
+    /// `None` when the RS equations have no usable root for this channel.
+    rs_rate: Option<f64>,
+    spa_rate: f64,
+}
+
+/// Natural log of the gamma function (Lanczos approximation).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let mut ser = 1.000000000190015;
+    for (j, c) in COEFFS.iter().enumerate() {
+        ser += c / (x + 1.0 + j as f64);
+    }
+    -tmp + (2.5066282746310005 * ser / x).ln()
+}
+
+/// Upper regularized incomplete gamma function Q(a, x).
+fn gamma_q(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let gln = ln_gamma(a);
+    if x < a + 1.0 {
+        // Series expansion of P(a, x).
+        let mut ap = a;
+        let mut del = 1.0 / a;
+        let mut sum = del;
+        for _ in 0..500 {
+            ap += 1.0;
+            del *= x / ap;
+            sum += del;
+            if del.abs() < sum.abs() * 1e-12 {
+                break;
+            }
+        }
+        1.0 - sum * (-x + a * x.ln() - gln).exp()
+    } else {
+        // Continued fraction for Q(a, x).
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / 1e-300;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..500 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < 1e-300 {
+                d = 1e-300;
+            }
+            c = b + an / c;
+            if c.abs() < 1e-300 {
+                c = 1e-300;
+            }
+            d = 1.0 / d;
+            let del = d * c;
+            h *= del;
+            if (del - 1.0).abs() < 1e-12 {
+                break;
+            }
+        }
+        (-x + a * x.ln() - gln).exp() * h
+    }
+}
+
+/// Westfeld-Pfitzmann chi-square test on the pairs of values (2k, 2k+1).
+/// LSB replacement equalizes each pair, so a p-value near 1 means embedding.
+/// Returns `None` when fewer than two pairs are populated enough to judge.
+fn chi_square_p_value(samples: &[u8]) -> Option<f64> {
+    let mut histogram = [0u64; 256];
+    for &sample in samples {
+        histogram[sample as usize] += 1;
+    }
+
+    let mut chi_square = 0.0;
+    let mut categories = 0;
+    for k in 0..128 {
+        let expected = (histogram[2 * k] + histogram[2 * k + 1]) as f64 / 2.0;
+        // Sparse pairs make the statistic unstable, as usual for chi-square.
+        if expected < 5.0 {
+            continue;
+        }
+        let observed = histogram[2 * k] as f64;
+        chi_square += (observed - expected).powi(2) / expected;
+        categories += 1;
+    }
+    if categories < 2 {
+        return None;
+    }
+    Some(gamma_q((categories - 1) as f64 / 2.0, chi_square / 2.0))
+}
+
+/// Runs the chi-square attack on the whole channel and on growing prefixes
+/// of it. Prefixes too sparse to judge are skipped rather than read as
+/// clean; a whole channel that sparse reports p = 0.
+fn chi_square_attack(samples: &[u8]) -> (f64, f64) {
+    let overall = chi_square_p_value(samples).unwrap_or(0.0);
+    let mut embedded_fraction = 0.0;
+    for percent in 1..=100 {
+        let prefix = &samples[..samples.len() * percent / 100];
+        match chi_square_p_value(prefix) {
+            None => continue,
+            Some(p) if p < 0.95 => break,
+            Some(_) => embedded_fraction = percent as f64 / 100.0,
+        }
+    }
+    (overall, embedded_fraction)
+}
+
+/// Counts regular and singular groups of four horizontal neighbours under
+/// the flipping mask `[0, 1, 1, 0]`, applied as F1 or, if `negative`, F-1.
+fn rs_counts(plane: &[u8], width: usize, negative: bool, flip_all: bool) -> (f64, f64) {
+    const MASK: [bool; 4] = [false, true, true, false];
+    let smoothness = |g: &[i32; 4]| -> i32 { g.windows(2).map(|w| (w[1] - w[0]).abs()).sum() };
+
+    let (mut regular, mut singular, mut groups) = (0u64, 0u64, 0u64);
+    for row in plane.chunks(width) {
+        for chunk in row.chunks_exact(4) {
+            let mut group = [0i32; 4];
+            for (g, &v) in group.iter_mut().zip(chunk) {
+                *g = if flip_all { (v ^ 1) as i32 } else { v as i32 };
+            }
+            let mut flipped = group;
+            for (value, &masked) in flipped.iter_mut().zip(MASK.iter()) {
+                if masked {
+                    *value = if negative {
+                        ((*value + 1) ^ 1) - 1
+                    } else {
+                        *value ^ 1
+                    };
+                }
+            }
+            let (before, after) = (smoothness(&group), smoothness(&flipped));
+            if after > before {
+                regular += 1;
+            } else if after < before {
+                singular += 1;
+            }
+            groups += 1;
+        }
+    }
+    if groups == 0 {
+        return (0.0, 0.0);
+    }
+    (
+        regular as f64 / groups as f64,
+        singular as f64 / groups as f64,
+    )
 }
 
-/// Retrieves a secret message from the image using LSB steganography.
-fn retrieve_message(image_path: &str) -> Result<String, String> {
-    let img = image::open(image_path).map_err(|e| e.to_string())?;
-    let (width, height) = img.dimensions();
+/// Fridrich-Goljan-Du RS analysis estimate of the embedding rate, or `None`
+/// when the quadratic is degenerate or has no real root. That happens on
+/// some natural and heavily embedded images and says nothing about whether
+/// the channel is clean.
+fn rs_analysis(plane: &[u8], width: usize) -> Option<f64> {
+    let (r_m, s_m) = rs_counts(plane, width, false, false);
+    let (r_neg_m, s_neg_m) = rs_counts(plane, width, true, false);
+    let (r_m1, s_m1) = rs_counts(plane, width, false, true);
+    let (r_neg_m1, s_neg_m1) = rs_counts(plane, width, true, true);
+
+    let d0 = r_m - s_m;
+    let d1 = r_m1 - s_m1;
+    let d_neg0 = r_neg_m - s_neg_m;
+    let d_neg1 = r_neg_m1 - s_neg_m1;
 
-    let img_buffer = img.to_rgba8();
+    let a = 2.0 * (d1 + d0);
+    let b = d_neg0 - d_neg1 - d1 - 3.0 * d0;
+    let c = d0 - d_neg0;
+
+    let x = if a.abs() < 1e-12 {
+        if b.abs() < 1e-12 {
+            return None;
+        }
+        -c / b
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let root1 = (-b + discriminant.sqrt()) / (2.0 * a);
+        let root2 = (-b - discriminant.sqrt()) / (2.0 * a);
+        if root1.abs() < root2.abs() {
+            root1
+        } else {
+            root2
+        }
+    };
+    let rate = x / (x - 0.5);
+    rate.is_finite().then(|| rate.clamp(0.0, 1.0))
+}
 
-    // Decode message length from the first 32 bits.
-    let mut message_length = 0u32;
-    for i in 0..32 {
-        let pixel_index = i / 3;
-        let channel_index = i % 3;
+/// Dumitrescu-Wu-Wang sample pair analysis over horizontal neighbours.
+fn sample_pair_analysis(plane: &[u8], width: usize) -> f64 {
+    let (mut x, mut y, mut k, mut pairs) = (0u64, 0u64, 0u64, 0u64);
+    for row in plane.chunks(width) {
+        for pair in row.windows(2) {
+            let (u, v) = (pair[0], pair[1]);
+            if (v % 2 == 0 && u < v) || (v % 2 == 1 && u > v) {
+                x += 1;
+            }
+            if (v % 2 == 0 && u > v) || (v % 2 == 1 && u < v) {
+                y += 1;
+            }
+            if u / 2 == v / 2 {
+                k += 1;
+            }
+            pairs += 1;
+        }
+    }
+    if k == 0 {
+        return 0.0;
+    }
 
-        let x = (pixel_index as u32 % width) as u32;
-        let y = (pixel_index as u32 / width) as u32;
+    let a = 2.0 * k as f64;
+    let b = 2.0 * (2.0 * x as f64 - pairs as f64);
+    let c = y as f64 - x as f64;
+    // Near full embedding sampling noise can push the discriminant just
+    // below zero; the double root is then the best estimate.
+    let discriminant = (b * b - 4.0 * a * c).max(0.0);
+    let root1 = (-b + discriminant.sqrt()) / (2.0 * a);
+    let root2 = (-b - discriminant.sqrt()) / (2.0 * a);
+    // The smaller root is the fraction of flipped samples, half the embedding rate.
+    (2.0 * root1.min(root2)).clamp(0.0, 1.0)
+}
 
+/// Saves the least significant bit of each RGB channel as a black or
+/// saturated pixel, which makes embedded regions visible as noise.
+fn write_bitplane(img_buffer: &RgbaImage, output_path: &str) -> Result<(), String> {
+    let bitplane = RgbaImage::from_fn(img_buffer.width(), img_buffer.height(), |x, y| {
         let pixel = img_buffer.get_pixel(x, y);
-        let color_channel = pixel[channel_index as usize];  // Explicitly cast to usize
+        image::Rgba([
+            (pixel[0] & 1) * 255,
+            (pixel[1] & 1) * 255,
+            (pixel[2] & 1) * 255,
+            255,
+        ])
+    });
+    bitplane.save(output_path).map_err(|e| e.to_string())
+}
+
+/// Runs the chi-square, RS and sample pair attacks on each RGB channel and
+/// draws the LSB plane to `bitplane_path`.
+fn detect(image_path: &str, bitplane_path: &str) -> Result<Vec<ChannelReport>, String> {
+    let img_buffer = image::open(image_path)
+        .map_err(|e| e.to_string())?
+        .to_rgba8();
+    let width = img_buffer.width() as usize;
+
+    let reports = (0..3)
+        .into_par_iter()
+        .map(|channel| {
+            let plane: Vec<u8> = img_buffer.pixels().map(|p| p[channel]).collect();
+            let (chi_square_p, chi_square_rate) = chi_square_attack(&plane);
+            ChannelReport {
+                chi_square_p,
+                chi_square_rate,
+                rs_rate: rs_analysis(&plane, width),
+                spa_rate: sample_pair_analysis(&plane, width),
+            }
+        })
+        .collect();
+
+    write_bitplane(&img_buffer, bitplane_path)?;
+    Ok(reports)
+}
+
+/// Distortion introduced by embedding, for tuning the hiding options.
+struct QualityReport {
+    /// Mean squared error over the RGB channels.
+    mse: f64,
+    /// Peak signal-to-noise ratio in dB; infinite for identical images.
+    psnr: f64,
+    /// Mean structural similarity of the luma planes.
+    ssim: f64,
+    /// RGBA channel values that differ.
+    changed_channels: usize,
+    /// Hidden bits per pixel.
+    bits_per_pixel: f64,
+}
+
+/// Luma (BT.601) of every pixel in an RGBA row, as used for SSIM.
+fn luma_row(row: &[u8]) -> Vec<f64> {
+    row.chunks_exact(4)
+        .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+        .collect()
+}
+
+/// Builds a `QualityReport` from pairs of RGBA rows fed in order, keeping
+/// only the rows the current SSIM window needs. SSIM is the mean over 8x8
+/// windows with a stride of 4.
+struct QualityAccumulator {
+    width: usize,
+    height: usize,
+    window: usize,
+    rows_seen: usize,
+    squared_error: f64,
+    changed_channels: usize,
+    luma: VecDeque<(Vec<f64>, Vec<f64>)>,
+    ssim_total: f64,
+    windows: usize,
+}
+
+impl QualityAccumulator {
+    const WINDOW: usize = 8;
+    const STRIDE: usize = 4;
+
+    fn new(width: usize, height: usize) -> Self {
+        let window = Self::WINDOW.min(width).min(height);
+        QualityAccumulator {
+            width,
+            height,
+            window,
+            rows_seen: 0,
+            squared_error: 0.0,
+            changed_channels: 0,
+            luma: VecDeque::with_capacity(window),
+            ssim_total: 0.0,
+            windows: 0,
+        }
+    }
+
+    fn add_row(&mut self, a: &[u8], b: &[u8]) {
+        for (pa, pb) in a.chunks_exact(4).zip(b.chunks_exact(4)) {
+            for c in 0..4 {
+                let delta = pa[c] as f64 - pb[c] as f64;
+                if c < 3 {
+                    self.squared_error += delta * delta;
+                }
+                if delta != 0.0 {
+                    self.changed_channels += 1;
+                }
+            }
+        }
+
+        if self.window == 0 {
+            return;
+        }
+        if self.luma.len() == self.window {
+            self.luma.pop_front();
+        }
+        self.luma.push_back((luma_row(a), luma_row(b)));
+        self.rows_seen += 1;
+        let top = self.rows_seen - self.luma.len();
+        if self.luma.len() == self.window && top.is_multiple_of(Self::STRIDE) {
+            self.add_window_row();
+        }
+    }
+
+    /// Adds every window whose top row is the oldest buffered row.
+    fn add_window_row(&mut self) {
+        const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+        const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+        let (luma, window) = (&self.luma, self.window);
+        let lefts: Vec<usize> = (0..=self.width - window).step_by(Self::STRIDE).collect();
+        let total: f64 = lefts
+            .par_iter()
+            .map(|&x| {
+                let n = (window * window) as f64;
+                let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                    (0.0, 0.0, 0.0, 0.0, 0.0);
+                for (row_a, row_b) in luma {
+                    for (&a, &b) in row_a[x..x + window].iter().zip(&row_b[x..x + window]) {
+                        sum_a += a;
+                        sum_b += b;
+                        sum_aa += a * a;
+                        sum_bb += b * b;
+                        sum_ab += a * b;
+                    }
+                }
+                let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+                let var_a = sum_aa / n - mean_a * mean_a;
+                let var_b = sum_bb / n - mean_b * mean_b;
+                let covariance = sum_ab / n - mean_a * mean_b;
+                ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                    / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+            })
+            .sum();
+        self.ssim_total += total;
+        self.windows += lefts.len();
+    }
+
+    fn finish(self, payload_bits: usize) -> QualityReport {
+        let pixels = self.width * self.height;
+        let mse = self.squared_error / (pixels * 3) as f64;
+        let psnr = if mse == 0.0 {
+            f64::INFINITY
+        } else {
+            10.0 * (255.0 * 255.0 / mse).log10()
+        };
+        QualityReport {
+            mse,
+            psnr,
+            ssim: self.ssim_total / self.windows.max(1) as f64,
+            changed_channels: self.changed_channels,
+            bits_per_pixel: payload_bits as f64 / pixels as f64,
+        }
+    }
+}
+
+/// Expands a row from `open_png_rows` to RGBA.
+fn rgba_row(row: &[u8], channels: usize) -> Vec<u8> {
+    if channels == 4 {
+        return row.to_vec();
+    }
+    row.chunks_exact(3)
+        .flat_map(|p| [p[0], p[1], p[2], 255])
+        .collect()
+}
+
+/// Computes MSE, PSNR, SSIM, the number of changed channels and the payload
+/// density between a cover image and its stego version. PNGs that can be
+/// streamed are compared a row at a time.
+fn quality_metrics(
+    cover_path: &str,
+    stego_path: &str,
+    payload_bits: usize,
+) -> Result<QualityReport, String> {
+    if let (Some(mut cover), Some(mut stego)) =
+        (open_png_rows(cover_path), open_png_rows(stego_path))
+    {
+        let (cover_info, stego_info) = (cover.info(), stego.info());
+        let (width, height) = (cover_info.width as usize, cover_info.height as usize);
+        if (cover_info.width, cover_info.height) != (stego_info.width, stego_info.height) {
+            return Err("The images have different dimensions.".to_string());
+        }
+        let channels = (
+            cover_info.color_type.samples(),
+            stego_info.color_type.samples(),
+        );
+        let mut accumulator = QualityAccumulator::new(width, height);
+        for _ in 0..height {
+            let a = cover.next_row().map_err(|e| e.to_string())?;
+            let a = rgba_row(a.ok_or("The PNG ended early.")?.data(), channels.0);
+            let b = stego.next_row().map_err(|e| e.to_string())?;
+            let b = rgba_row(b.ok_or("The PNG ended early.")?.data(), channels.1);
+            accumulator.add_row(&a, &b);
+        }
+        return Ok(accumulator.finish(payload_bits));
+    }
+
+    let cover = image::open(cover_path)
+        .map_err(|e| e.to_string())?
+        .to_rgba8();
+    let stego = image::open(stego_path)
+        .map_err(|e| e.to_string())?
+        .to_rgba8();
+    if cover.dimensions() != stego.dimensions() {
+        return Err("The images have different dimensions.".to_string());
+    }
+    let (width, height) = (cover.width() as usize, cover.height() as usize);
+    let mut accumulator = QualityAccumulator::new(width, height);
+    for (a, b) in cover
+        .as_raw()
+        .chunks_exact(width * 4)
+        .zip(stego.as_raw().chunks_exact(width * 4))
+    {
+        accumulator.add_row(a, b);
+    }
+    Ok(accumulator.finish(payload_bits))
+}
+
+/// Pixel differences between a cover and its stego version.
+struct DiffReport {
+    pixels: usize,
+    changed_pixels: usize,
+    changed_channels: usize,
+    max_delta: u8,
+}
+
+/// Compares two images of the same size and optionally draws the changed
+/// pixels in red over a dimmed copy of the cover.
+fn diff_images(
+    cover_path: &str,
+    stego_path: &str,
+    output_path: Option<&str>,
+) -> Result<DiffReport, String> {
+    let cover = image::open(cover_path)
+        .map_err(|e| e.to_string())?
+        .to_rgba8();
+    let stego = image::open(stego_path)
+        .map_err(|e| e.to_string())?
+        .to_rgba8();
+    if cover.dimensions() != stego.dimensions() {
+        return Err("The images have different dimensions.".to_string());
+    }
+
+    let mut report = DiffReport {
+        pixels: (cover.width() as usize) * (cover.height() as usize),
+        changed_pixels: 0,
+        changed_channels: 0,
+        max_delta: 0,
+    };
+    let mut visualization = RgbaImage::new(cover.width(), cover.height());
+    for ((a, b), out) in cover
+        .pixels()
+        .zip(stego.pixels())
+        .zip(visualization.pixels_mut())
+    {
+        let changed = (0..4).filter(|&c| a[c] != b[c]).count();
+        report.changed_channels += changed;
+        report.max_delta = (0..4)
+            .map(|c| a[c].abs_diff(b[c]))
+            .fold(report.max_delta, u8::max);
+        *out = if changed > 0 {
+            report.changed_pixels += 1;
+            image::Rgba([255, 0, 0, 255])
+        } else {
+            let luma = ((a[0] as u32 * 299 + a[1] as u32 * 587 + a[2] as u32 * 114) / 4000) as u8;
+            image::Rgba([luma, luma, luma, 255])
+        };
+    }
+
+    if let Some(output_path) = output_path {
+        visualization.save(output_path).map_err(|e| e.to_string())?;
+    }
+    Ok(report)
+}
 
-        message_length = (message_length << 1) | (color_channel & 1) as u32;
+/// Times the whole-image and row-by-row paths on one PNG cover, and the
+/// texture map with one thread and with all of them. Returns labelled timings.
+fn bench(cover_path: &str, payload_len: usize) -> Result<Vec<(&'static str, Duration)>, String> {
+    let reader = open_png_rows(cover_path)
+        .ok_or_else(|| "Benchmarks need an 8-bit, non-interlaced RGB or RGBA PNG.".to_string())?;
+    let mut data = vec![0u8; payload_len];
+    rand::thread_rng().fill_bytes(&mut data);
+    let body = encode_payload(&Payload {
+        filename: String::new(),
+        data,
+    })?;
+    let header = Header {
+        flags: 0,
+        mode: EmbedMode::DEFAULT,
+        ecc_parity_bits: 0,
+        shard: ShardInfo::SINGLE,
+        body_len: body.len(),
+    };
+    let coded_header = ecc_encode(&header.to_bytes(), HEADER_PARITY_BITS);
+    let options = HideOptions {
+        password: None,
+        mode: EmbedMode::DEFAULT,
+        ecc_parity_bits: 0,
+        matrix_k: 0,
+        lsb_matching: false,
+        adaptive: false,
+        text_encoding: TextEncoding::ZeroWidth,
+    };
+    let temp = |name: &str| {
+        std::env::temp_dir()
+            .join(format!("stegano-bench-{}-{}.png", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    };
+    let (whole_path, rows_path) = (temp("whole"), temp("rows"));
+
+    let mut timings = Vec::new();
+    let start = Instant::now();
+    embed_in_carrier(cover_path, &whole_path, &coded_header, &body, &options)?;
+    timings.push(("whole-image hide", start.elapsed()));
+    let start = Instant::now();
+    stream_embed_png(
+        reader,
+        cover_path,
+        &rows_path,
+        &coded_header,
+        &body,
+        EmbedMode::DEFAULT,
+        false,
+    )?;
+    timings.push(("row-by-row hide", start.elapsed()));
+
+    let start = Instant::now();
+    let whole = read_body_in_carrier(&rows_path, None)?;
+    timings.push(("whole-image extract", start.elapsed()));
+    let start = Instant::now();
+    let rows = open_png_rows(&whole_path)
+        .map(stream_read_body)
+        .transpose()?
+        .flatten()
+        .ok_or_else(|| "The benchmark output could not be streamed.".to_string())?;
+    timings.push(("row-by-row extract", start.elapsed()));
+    let _ = fs::remove_file(&whole_path);
+    let _ = fs::remove_file(&rows_path);
+    if whole.body != body || rows.body != body {
+        return Err("The two paths disagree on the hidden body.".to_string());
+    }
+
+    let carrier = open_carrier(cover_path, None)?;
+    let units: Vec<usize> = (0..carrier.units()).collect();
+    let start = Instant::now();
+    let serial: Vec<u64> = units.iter().map(|&unit| carrier.texture(unit, 1)).collect();
+    timings.push(("texture map, 1 thread", start.elapsed()));
+    let start = Instant::now();
+    let parallel: Vec<u64> = units
+        .par_iter()
+        .map(|&unit| carrier.texture(unit, 1))
+        .collect();
+    timings.push(("texture map, rayon", start.elapsed()));
+    if serial != parallel {
+        return Err("The texture maps disagree.".to_string());
     }
+    Ok(timings)
+}
 
-    // Decode the message from the pixels.
-    let mut message_bytes = Vec::new();
-    for i in 0..message_length {
-        let mut byte = 0u8;
-        for bit_index in 0..8 {
-            let pixel_index = (32 + i * 8 + bit_index) / 3;
-            let channel_index = (32 + i * 8 + bit_index) % 3;
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
 
-            let x = (pixel_index as u32 % width) as u32;
-            let y = (pixel_index as u32 / width) as u32;
+/// Payload and embedding options shared by `hide` and `split`.
+#[derive(Args)]
+struct EmbedArgs {
+    /// File to hide; reads stdin when omitted or "-"
+    #[arg(short, long)]
+    input: Option<String>,
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Low bits used in each channel (1-4)
+    #[arg(long, default_value_t = 1)]
+    bits: u8,
+    /// Channels to embed in, any of r, g, b, a
+    #[arg(long, default_value = "rgb")]
+    channels: String,
+    /// Hamming parity bits per block, 2 (strongest) to 8; 0 disables error correction
+    #[arg(long, default_value_t = 0)]
+    ecc: u8,
+    /// Matrix embedding parameter k (2-7); 0 uses plain LSB
+    #[arg(long, default_value_t = 0)]
+    matrix: u8,
+    /// Change bits by ±1 instead of replacing them
+    #[arg(long)]
+    lsb_matching: bool,
+    /// Prefer textured regions of the cover
+    #[arg(long)]
+    adaptive: bool,
+    /// How to write bits into .txt covers
+    #[arg(long, value_enum, default_value_t = TextEncoding::ZeroWidth)]
+    text: TextEncoding,
+}
 
-            let pixel = img_buffer.get_pixel(x, y);
-            let color_channel = pixel[channel_index as usize];  // Explicitly cast to usize
+impl EmbedArgs {
+    /// Reads the payload from the input file or stdin.
+    fn payload(&self) -> Payload {
+        match self.input.as_deref() {
+            None | Some("-") => {
+                let mut data = Vec::new();
+                io::stdin()
+                    .read_to_end(&mut data)
+                    .unwrap_or_else(|e| fail("reading stdin", e.to_string()));
+                Payload {
+                    filename: String::new(),
+                    data,
+                }
+            }
+            Some(path) => Payload {
+                filename: Path::new(path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                data: fs::read(path).unwrap_or_else(|e| fail("reading the payload", e.to_string())),
+            },
+        }
+    }
 
-            byte = (byte << 1) | (color_channel & 1);
+    fn options(&self) -> HideOptions<'_> {
+        HideOptions {
+            password: self.password.as_deref(),
+            mode: EmbedMode::new(self.bits, &self.channels)
+                .unwrap_or_else(|e| fail("parsing the mode", e)),
+            ecc_parity_bits: self.ecc,
+            matrix_k: self.matrix,
+            lsb_matching: self.lsb_matching,
+            adaptive: self.adaptive,
+            text_encoding: self.text,
         }
-        message_bytes.push(byte);
     }
+}
+
+/// Prints how many cover bits an embed changed.
+fn print_stats(stats: &EmbedStats) {
+    println!(
+        "Changed {} of {} cover bits for {} message bits ({:.3} changes per bit); plain LSB would change {}.",
+        stats.changes,
+        stats.cover_bits_used,
+        stats.message_bits,
+        stats.changes as f64 / stats.message_bits.max(1) as f64,
+        stats.naive_changes
+    );
+}
 
-    String::from_utf8(message_bytes).map_err(|e| e.to_string())
+#[derive(Subcommand)]
+enum Commands {
+    /// Hide a file (or stdin) inside an image or WAV cover
+    Hide {
+        cover: String,
+        output: String,
+        #[command(flatten)]
+        embed: EmbedArgs,
+    },
+    /// Split a file (or stdin) across several covers
+    Split {
+        /// Directory for the stego files
+        output_dir: String,
+        #[arg(required = true)]
+        covers: Vec<String>,
+        /// Erasure-code the payload so any this many covers rebuild it; each then holds about 1/threshold of it. 0 needs them all
+        #[arg(short, long, default_value_t = 0)]
+        threshold: u8,
+        #[command(flatten)]
+        embed: EmbedArgs,
+    },
+    /// Extract a hidden payload from a stego file, or reassemble one from a directory of parts
+    Extract {
+        stego: String,
+        /// Required if one was used to hide; without it the data cannot even be located
+        #[arg(short, long)]
+        password: Option<String>,
+        /// Where to write the payload, "-" for stdout; defaults to the stored file name,
+        /// which never replaces an existing file
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Show how many bytes a cover can hold
+    Capacity {
+        cover: String,
+        #[arg(long, default_value_t = 1)]
+        bits: u8,
+        #[arg(long, default_value = "rgb")]
+        channels: String,
+        /// How bits would be written into a .txt cover
+        #[arg(long, value_enum, default_value_t = TextEncoding::ZeroWidth)]
+        text: TextEncoding,
+    },
+    /// Write a text stego file back out without its hidden bits
+    Strip { stego: String, output: String },
+    /// Look for LSB embedding with chi-square, RS and sample pair analysis
+    Detect {
+        image: String,
+        /// Where to draw the LSB bit plane
+        #[arg(long, default_value = "bitplane.png")]
+        bitplane: String,
+    },
+    /// Report the pixels changed between a cover and a stego image
+    Diff {
+        cover: String,
+        stego: String,
+        /// Where to draw the changed pixels
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Compare the whole-image and row-by-row paths on a PNG cover. A
+    /// development aid, so it is left out of the help.
+    #[command(hide = true)]
+    Bench {
+        cover: String,
+        /// Size of the random payload
+        #[arg(long, default_value_t = 1 << 20)]
+        bytes: usize,
+    },
+}
+
+/// Prints the error to stderr and exits with a failure status.
+fn fail(context: &str, e: String) -> ! {
+    eprintln!("An error occurred while {}: {}", context, e);
+    std::process::exit(1);
 }
 
 fn main() {
-    let mut input_image_path = String::new();
-    let mut output_image_path = String::new();
-    let mut secret_message = String::new();
-
-    print!("Enter the path of the input image: ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut input_image_path).unwrap();
-    let input_image_path = input_image_path.trim();
-
-    print!("Enter the path for the output image: ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut output_image_path).unwrap();
-    let output_image_path = output_image_path.trim();
-
-    print!("Enter the secret message to hide: ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut secret_message).unwrap();
-    let secret_message = secret_message.trim();
-
-    match hide_message(input_image_path, output_image_path, secret_message) {
-        Ok(_) => println!("Message hidden successfully in {}", output_image_path),
-        Err(e) => {
-            println!("An error occurred while hiding the message: {}", e);
-            return;
+    let cli = Cli::parse();
+
+    match cli.command {
+        Commands::Hide {
+            cover,
+            output,
+            embed,
+        } => {
+            let payload = embed.payload();
+            let stats = hide_message(&cover, &output, &payload, &embed.options())
+                .unwrap_or_else(|e| fail("hiding the message", e));
+            println!("Message hidden successfully in {}", output);
+            print_stats(&stats);
+            if !is_wav_path(&cover) && !is_text_path(&cover) {
+                // Density counts the user's data, not the header, framing,
+                // encryption or ECC overhead.
+                let quality = quality_metrics(&cover, &output, payload.data.len() * 8)
+                    .unwrap_or_else(|e| fail("measuring image quality", e));
+                println!(
+                    "MSE {:.5}, PSNR {:.2} dB, SSIM {:.5}, {} channels modified, {:.4} bits per pixel",
+                    quality.mse,
+                    quality.psnr,
+                    quality.ssim,
+                    quality.changed_channels,
+                    quality.bits_per_pixel
+                );
+            }
+        }
+        Commands::Split {
+            output_dir,
+            covers,
+            threshold,
+            embed,
+        } => {
+            let results = hide_split(
+                &covers,
+                &output_dir,
+                &embed.payload(),
+                &embed.options(),
+                threshold,
+            )
+            .unwrap_or_else(|e| fail("splitting the message", e));
+            for (path, stats) in &results {
+                println!("Wrote part {}", path.display());
+                print_stats(stats);
+            }
+        }
+        Commands::Extract {
+            stego,
+            password,
+            output,
+        } => {
+            let (payload, report) = if Path::new(&stego).is_dir() {
+                retrieve_split(&stego, password.as_deref())
+            } else {
+                retrieve_message(&stego, password.as_deref())
+            }
+            .unwrap_or_else(|e| fail("retrieving the message", e));
+            // Status goes to stderr so the payload can be piped from stdout.
+            if report.header_errors + report.body_errors > 0 {
+                eprintln!(
+                    "Corrected {} bit errors ({} in the header, {} in the body).",
+                    report.header_errors + report.body_errors,
+                    report.header_errors,
+                    report.body_errors
+                );
+            }
+
+            let (extracted_path, stored_name) = match output {
+                Some(path) if path != "-" => (Some(PathBuf::from(path)), false),
+                Some(_) => (None, false),
+                // Only keep the final path component so a crafted name cannot escape the directory.
+                None => (
+                    Path::new(&payload.filename).file_name().map(PathBuf::from),
+                    true,
+                ),
+            };
+            match extracted_path {
+                Some(path) => {
+                    // The stored name comes from the stego file, so it may
+                    // only create new files, never replace existing ones.
+                    let written = if stored_name {
+                        File::options()
+                            .write(true)
+                            .create_new(true)
+                            .open(&path)
+                            .and_then(|mut file| file.write_all(&payload.data))
+                    } else {
+                        fs::write(&path, &payload.data)
+                    };
+                    match written {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => fail(
+                            "writing the extracted file",
+                            format!(
+                                "{} already exists; choose where to write it with -o.",
+                                path.display()
+                            ),
+                        ),
+                        Err(e) => fail("writing the extracted file", e.to_string()),
+                    }
+                    eprintln!(
+                        "Extracted {} bytes to {}",
+                        payload.data.len(),
+                        path.display()
+                    );
+                }
+                None => io::stdout()
+                    .write_all(&payload.data)
+                    .unwrap_or_else(|e| fail("writing to stdout", e.to_string())),
+            }
+        }
+        Commands::Capacity {
+            cover,
+            bits,
+            channels,
+            text,
+        } => {
+            let mode =
+                EmbedMode::new(bits, &channels).unwrap_or_else(|e| fail("parsing the mode", e));
+            let carrier =
+                open_carrier(&cover, Some(text)).unwrap_or_else(|e| fail("opening the cover", e));
+            println!("{} bytes", capacity(carrier.as_ref(), mode));
+        }
+        Commands::Strip { stego, output } => {
+            let carrier =
+                TextCarrier::open(&stego, None).unwrap_or_else(|e| fail("opening the text", e));
+            fs::write(&output, carrier.clean_text())
+                .unwrap_or_else(|e| fail("writing the text", e.to_string()));
+            println!("Wrote the text without its hidden bits to {}", output);
+        }
+        Commands::Detect { image, bitplane } => {
+            let reports =
+                detect(&image, &bitplane).unwrap_or_else(|e| fail("analysing the image", e));
+            println!("Channel  Chi-square p  Chi-square rate  RS rate  SPA rate");
+            for (name, report) in ["Red", "Green", "Blue"].iter().zip(&reports) {
+                println!(
+                    "{:<7}  {:>12.4}  {:>15.2}  {:>7}  {:>8.3}",
+                    name,
+                    report.chi_square_p,
+                    report.chi_square_rate,
+                    report
+                        .rs_rate
+                        .map_or("n/a".to_string(), |rate| format!("{:.3}", rate)),
+                    report.spa_rate
+                );
+            }
+            // Channels where RS found no root only contribute their SPA rate.
+            let rates: Vec<f64> = reports
+                .iter()
+                .flat_map(|r| r.rs_rate.into_iter().chain([r.spa_rate]))
+                .collect();
+            let estimate = rates.iter().sum::<f64>() / rates.len() as f64;
+            println!("Estimated embedding rate: {:.1}%", estimate * 100.0);
+            println!("LSB bit plane written to {}", bitplane);
+        }
+        Commands::Diff {
+            cover,
+            stego,
+            output,
+        } => {
+            let report = diff_images(&cover, &stego, output.as_deref())
+                .unwrap_or_else(|e| fail("comparing the images", e));
+            println!(
+                "{} of {} pixels changed ({:.2}%), {} channels, largest change {}",
+                report.changed_pixels,
+                report.pixels,
+                report.changed_pixels as f64 * 100.0 / report.pixels.max(1) as f64,
+                report.changed_channels,
+                report.max_delta
+            );
+            if let Some(output) = output {
+                println!("Changed pixels drawn to {}", output);
+            }
+        }
+        Commands::Bench { cover, bytes } => {
+            let timings = bench(&cover, bytes).unwrap_or_else(|e| fail("benchmarking", e));
+            for (label, duration) in timings {
+                println!("{:<22} {:>9.3} s", label, duration.as_secs_f64());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path in the temp directory that no other test uses.
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("stegano-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn random_image(rng: &mut ChaCha20Rng) -> RgbaImage {
+        let (width, height) = (rng.gen_range(1..64), rng.gen_range(1..64));
+        RgbaImage::from_fn(width, height, |_, _| image::Rgba(rng.gen()))
+    }
+
+    /// Writes raw header bytes where `embed_in_carrier` puts the header of
+    /// an unkeyed embedding, bypassing the checks `Header::to_bytes` implies.
+    fn plant_header(image: RgbaImage, header: &[u8]) -> RgbaImage {
+        let mut carrier = ImageCarrier {
+            buffer: image,
+            has_alpha: true,
+        };
+        let order = embedding_order(carrier.units(), None);
+        let slots = Slots::new(&carrier, &order, EmbedMode::DEFAULT, 0);
+        write_bits(
+            &mut carrier,
+            &slots,
+            &ecc_encode(header, HEADER_PARITY_BITS),
+        );
+        carrier.buffer
+    }
+
+    /// Both readers must reject the file rather than panic or return data.
+    /// The streaming reader may instead hand adaptive and matrix embeddings
+    /// back to the in-memory one.
+    fn assert_rejected(image: &RgbaImage, name: &str) {
+        let path = temp_path(name);
+        image.save(&path).unwrap();
+        let whole = read_body_in_carrier(&path, None);
+        let streamed = stream_read_body(open_png_rows(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(whole.is_err(), "read_body_in_carrier accepted {}", name);
+        assert!(
+            !matches!(streamed, Ok(Some(_))),
+            "stream_read_body accepted {}",
+            name
+        );
+    }
+
+    /// Writes a second of a full-scale 440 Hz sine, a quarter cycle apart
+    /// on each channel, so samples come close to both limits of the bit depth.
+    fn sine_wav(path: &str, bits_per_sample: u16, channels: u16) {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate: 8000,
+            bits_per_sample,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let amplitude = ((1i64 << (bits_per_sample - 1)) - 1) as f64;
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for n in 0..8000 {
+            for channel in 0..channels {
+                let phase = n as f64 * 440.0 / 8000.0 + channel as f64 / 4.0;
+                let sample = (amplitude * (phase * std::f64::consts::TAU).sin()).round();
+                writer.write_sample(sample as i32).unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn wav_round_trips() {
+        let payload = Payload {
+            filename: "secret.bin".to_string(),
+            data: (0..200).map(|i| (i * 37 % 256) as u8).collect(),
+        };
+        for bits in [8, 16, 24] {
+            for channels in [1, 2] {
+                for password in [None, Some("hunter2")] {
+                    let name = format!("{}bit-{}ch-{}", bits, channels, password.is_some());
+                    let cover = temp_path(&format!("{}-cover.wav", name));
+                    let output = temp_path(&format!("{}-stego.wav", name));
+                    sine_wav(&cover, bits, channels);
+                    let options = HideOptions {
+                        password,
+                        mode: EmbedMode::DEFAULT,
+                        ecc_parity_bits: 0,
+                        matrix_k: 0,
+                        lsb_matching: false,
+                        adaptive: false,
+                        text_encoding: TextEncoding::ZeroWidth,
+                    };
+                    let hidden = hide_message(&cover, &output, &payload, &options);
+                    let retrieved = retrieve_message(&output, password);
+                    let _ = fs::remove_file(&cover);
+                    let _ = fs::remove_file(&output);
+                    hidden.unwrap_or_else(|e| panic!("hiding in {}: {}", name, e));
+                    let (extracted, _) =
+                        retrieved.unwrap_or_else(|e| panic!("extracting from {}: {}", name, e));
+                    assert_eq!(extracted.filename, payload.filename, "{}", name);
+                    assert_eq!(extracted.data, payload.data, "{}", name);
+                }
+            }
         }
     }
 
-    println!("Retrieving the hidden message from the output image...");
-    match retrieve_message(output_image_path) {
-        Ok(message) => println!("Extracted Message: {}", message),
-        Err(e) => println!("An error occurred while retrieving the message: {}", e),
+    /// Writes a 4:2:0 baseline JPEG with partial MCUs and restart markers,
+    /// coded with Huffman tables that give every symbol an 8-bit code.
+    fn subsampled_jpeg(path: &str) {
+        fn segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+            out.extend([0xFF, marker]);
+            out.extend((payload.len() as u16 + 2).to_be_bytes());
+            out.extend(payload);
+        }
+        let (width, height) = (90u16, 70u16);
+        let (mcus_wide, mcus_high, restart_interval) = (6, 5, 4);
+        let mut out = vec![0xFF, 0xD8];
+        segment(&mut out, 0xDB, &[[0].as_slice(), &[2; 64]].concat());
+        let mut frame = vec![8];
+        frame.extend(height.to_be_bytes());
+        frame.extend(width.to_be_bytes());
+        frame.extend([3, 1, 0x22, 0, 2, 0x11, 0, 3, 0x11, 0]);
+        segment(&mut out, 0xC0, &frame);
+        let mut counts = [0; 16];
+        counts[7] = 12;
+        let dc_symbols: Vec<u8> = (0..12).collect();
+        let dc = HuffmanTable::new(&counts, &dc_symbols).unwrap();
+        let mut tables = [[0x00].as_slice(), &counts, &dc_symbols].concat();
+        counts[7] = 255;
+        let ac_symbols: Vec<u8> = (0..255).collect();
+        let ac = HuffmanTable::new(&counts, &ac_symbols).unwrap();
+        tables.extend([[0x10].as_slice(), &counts, &ac_symbols].concat());
+        segment(&mut out, 0xC4, &tables);
+        segment(&mut out, 0xDD, &(restart_interval as u16).to_be_bytes());
+        segment(&mut out, 0xDA, &[3, 1, 0, 2, 0, 3, 0, 0, 63, 0]);
+
+        let mut rng = ChaCha20Rng::seed_from_u64(30);
+        let mut writer = BitWriter::default();
+        let mut predictions = [0; 3];
+        for mcu in 0..mcus_wide * mcus_high {
+            if mcu > 0 && mcu % restart_interval == 0 {
+                out.extend(std::mem::take(&mut writer).finish());
+                out.extend([0xFF, 0xD0 + ((mcu / restart_interval - 1) % 8) as u8]);
+                predictions = [0; 3];
+            }
+            for (slot, blocks) in [4, 1, 1].into_iter().enumerate() {
+                for _ in 0..blocks {
+                    let mut block = [0i16; 64];
+                    block[0] = rng.gen_range(-60..60);
+                    for coefficient in &mut block[1..20] {
+                        *coefficient = rng.gen_range(-12..=12);
+                    }
+                    encode_block(&mut writer, &dc, &ac, &mut predictions[slot], &block).unwrap();
+                }
+            }
+        }
+        out.extend(writer.finish());
+        out.extend([0xFF, 0xD9]);
+        fs::write(path, out).unwrap();
+    }
+
+    #[test]
+    fn subsampled_jpeg_round_trips() {
+        let cover = temp_path("420-cover.jpg");
+        let output = temp_path("420-stego.jpg");
+        subsampled_jpeg(&cover);
+        let untouched = JpegCarrier::open(&cover).unwrap().encode().unwrap();
+        assert!(
+            untouched == fs::read(&cover).unwrap(),
+            "re-encoding changed the file"
+        );
+
+        let payload = Payload {
+            filename: "secret.txt".to_string(),
+            data: b"hidden in the coefficients".to_vec(),
+        };
+        let options = HideOptions {
+            password: Some("hunter2"),
+            mode: EmbedMode::DEFAULT,
+            ecc_parity_bits: 0,
+            matrix_k: 0,
+            lsb_matching: false,
+            adaptive: false,
+            text_encoding: TextEncoding::ZeroWidth,
+        };
+        let hidden = hide_message(&cover, &output, &payload, &options);
+        let retrieved = retrieve_message(&output, Some("hunter2"));
+        let decoded = [&cover, &output].map(|path| image::open(path).map(|img| img.to_rgb8()));
+        let _ = fs::remove_file(&cover);
+        let _ = fs::remove_file(&output);
+        hidden.unwrap();
+        assert_eq!(retrieved.unwrap().0.data, payload.data);
+
+        // Flipping magnitude LSBs moves pixels by a level or two; a broken
+        // scan layout decodes as noise.
+        let [cover, stego] = decoded.map(Result::unwrap);
+        let deltas: Vec<i32> = cover
+            .as_raw()
+            .iter()
+            .zip(stego.as_raw())
+            .map(|(&a, &b)| (a as i32 - b as i32).abs())
+            .collect();
+        let mean = deltas.iter().sum::<i32>() as f64 / deltas.len() as f64;
+        assert!(mean < 2.0, "mean pixel change {}", mean);
+        assert!(
+            deltas.iter().all(|&d| d <= 16),
+            "a pixel changed by {}",
+            deltas.iter().max().unwrap()
+        );
+    }
+
+    #[test]
+    fn text_round_trips_keep_zero_width_joins() {
+        // A ZWJ emoji sequence and a Persian word with a ZWNJ on every line.
+        let line = "The family \u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467} said \u{0646}\u{0645}\u{06CC}\u{200C}\u{062E}\u{0648}\u{0627}\u{0647}\u{0645} twice\n";
+        let text = line.repeat(120);
+        let payload = Payload {
+            filename: "note.txt".to_string(),
+            data: b"between the words".to_vec(),
+        };
+        for encoding in [TextEncoding::ZeroWidth, TextEncoding::Whitespace] {
+            let cover = temp_path(&format!("{:?}-cover.txt", encoding));
+            let output = temp_path(&format!("{:?}-stego.txt", encoding));
+            fs::write(&cover, &text).unwrap();
+            let options = HideOptions {
+                password: None,
+                mode: EmbedMode::DEFAULT,
+                ecc_parity_bits: 0,
+                matrix_k: 0,
+                lsb_matching: false,
+                adaptive: false,
+                text_encoding: encoding,
+            };
+            let hidden = hide_message(&cover, &output, &payload, &options);
+            let retrieved = retrieve_message(&output, None);
+            let stripped = TextCarrier::open(&output, None).map(|carrier| carrier.clean_text());
+            let _ = fs::remove_file(&cover);
+            let _ = fs::remove_file(&output);
+            hidden.unwrap_or_else(|e| panic!("hiding with {:?}: {}", encoding, e));
+            let (extracted, _) =
+                retrieved.unwrap_or_else(|e| panic!("extracting with {:?}: {}", encoding, e));
+            assert_eq!(extracted.data, payload.data, "{:?}", encoding);
+            assert_eq!(stripped.unwrap(), text, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn random_images_are_rejected() {
+        let mut rng = ChaCha20Rng::seed_from_u64(37);
+        for i in 0..200 {
+            let image = random_image(&mut rng);
+            assert_rejected(&image, &format!("random-{}.png", i));
+        }
+    }
+
+    #[test]
+    fn garbage_headers_are_rejected() {
+        let mut rng = ChaCha20Rng::seed_from_u64(38);
+        for i in 0..300 {
+            let image = random_image(&mut rng);
+            let mut header = [0; HEADER_LEN];
+            rng.fill_bytes(&mut header);
+            header[..4].copy_from_slice(MAGIC);
+            header[4] = FORMAT_VERSION;
+            // Four bits in each of four channels, in bytes.
+            let capacity = image.width() * image.height() * 2;
+            match i % 3 {
+                // A length no cover of this size can hold.
+                0 => header[15..19]
+                    .copy_from_slice(&rng.gen_range(capacity..=u32::MAX).to_be_bytes()),
+                // An unused bit depth or an empty channel mask.
+                1 => {
+                    header[6] = match rng.gen() {
+                        true => rng.gen_range(0x40..=0xFF),
+                        false => rng.gen_range(0..4) << 4,
+                    }
+                }
+                // Parity bits outside 2-8.
+                _ => header[7] = *[1, 9, 200, 255].choose(&mut rng).unwrap(),
+            }
+            let image = if image.width() * image.height() >= header_units(4) as u32 {
+                plant_header(image, &header)
+            } else {
+                image
+            };
+            assert_rejected(&image, &format!("garbage-{}.png", i));
+        }
     }
 }
@@ -1,20 +1,350 @@
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, Weekday};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use fs2::FileExt;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
 use serde::{Deserialize, Serialize};
-use std::fs::{self, OpenOptions};
-use std::io::{self, Write};
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 
-const FILE_PATH: &str = "todo.json";
+const FILE_NAME: &str = "todo.json";
+const DB_FILE_NAME: &str = "todo.db";
+const SCHEMA_VERSION: u32 = 2;
+/// The list tasks go to when no `--list` is given. It always exists.
+const DEFAULT_LIST: &str = "default";
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Priority {
+    Low,
+    Medium,
+    High,
+}
+
+// Fields added after the first release default when missing, so older
+// todo.json files still load.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Task {
     id: usize,
     description: String,
     done: bool,
+    #[serde(default)]
+    priority: Option<Priority>,
+    #[serde(default)]
+    due: Option<NaiveDate>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    created: Option<DateTime<Local>>,
+    #[serde(default)]
+    completed: Option<DateTime<Local>>,
+    #[serde(default = "default_list")]
+    list: String,
+}
+
+fn default_list() -> String {
+    DEFAULT_LIST.to_string()
+}
+
+/// The todo.json contents. IDs come from `next_id`, which only grows, so an
+/// ID is never reused after its task is removed, and are unique across lists.
+#[derive(Debug, Serialize, Deserialize)]
+struct Store {
+    version: u32,
+    next_id: usize,
+    /// Named lists, including empty ones; version 1 files have none.
+    #[serde(default)]
+    lists: Vec<String>,
+    tasks: Vec<Task>,
+}
+
+impl Store {
+    fn has_list(&self, name: &str) -> bool {
+        self.lists.iter().any(|list| list == name)
+    }
+
+    /// Brings an older store up to `SCHEMA_VERSION`. Returns whether
+    /// anything changed.
+    fn upgrade(&mut self) -> bool {
+        if self.version >= SCHEMA_VERSION {
+            return false;
+        }
+        // Version 2 added named lists; register every list tasks refer to.
+        for list in std::iter::once(DEFAULT_LIST).chain(self.tasks.iter().map(|t| t.list.as_str()))
+        {
+            if !self.lists.iter().any(|known| known == list) {
+                self.lists.push(list.to_string());
+            }
+        }
+        self.version = SCHEMA_VERSION;
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    /// A single JSON file, rewritten on every change
+    Json,
+    /// A SQLite database, updated one row at a time
+    Sqlite,
+}
+
+/// What a command changed, so backends that can write less than the whole
+/// store know which rows to touch.
+#[derive(Debug, Clone, Copy)]
+enum Change {
+    /// The task with this id was added, edited or removed.
+    Task(usize),
+    /// Lists were added or renamed, possibly moving tasks with them.
+    Lists,
+    /// Anything; the whole store is written.
+    All,
+}
+
+/// Where tasks are kept. Implementations report errors through `fail`, like
+/// the rest of the program.
+trait TaskStore {
+    /// Where the store lives, for messages.
+    fn path(&self) -> &Path;
+    /// Reads the whole store as it is stored, possibly in an older layout.
+    fn load_stored(&mut self) -> Store;
+    /// Persists `store` after a command made `change` to it.
+    fn save(&mut self, store: &Store, change: Change);
+
+    /// Reads the whole store, upgrading older layouts and saving the result.
+    fn load(&mut self) -> Store {
+        let mut store = self.load_stored();
+        if store.version > SCHEMA_VERSION {
+            fail(
+                &format!("reading {}", self.path().display()),
+                format!(
+                    "it uses schema version {}, newer than this program supports ({})",
+                    store.version, SCHEMA_VERSION
+                ),
+            );
+        }
+        if store.upgrade() {
+            self.save(&store, Change::All);
+            eprintln!(
+                "Upgraded {} to schema version {}.",
+                self.path().display(),
+                SCHEMA_VERSION
+            );
+        }
+        store
+    }
+}
+
+/// The tasks and lists an operation touched, as they were at one point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Snapshot {
+    tasks: Vec<Task>,
+    /// Only set when the operation changed the lists themselves.
+    lists: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum EntryKind {
+    /// A command changed the store.
+    Do,
+    /// `undo` reverted entry `of`.
+    Undo { of: usize },
+    /// `redo` re-applied entry `of`.
+    Redo { of: usize },
+}
+
+/// One line of the operation log. `before` and `after` cover only what
+/// changed, which is enough to move the store either way.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    seq: usize,
+    at: DateTime<Local>,
+    #[serde(flatten)]
+    kind: EntryKind,
+    summary: String,
+    before: Snapshot,
+    after: Snapshot,
+}
+
+/// Captures what a command is about to change, so the change can be saved,
+/// logged and later undone.
+struct Edit {
+    ids: Vec<usize>,
+    lists: bool,
+    before: Snapshot,
+}
+
+impl Edit {
+    fn begin(store: &Store, ids: Vec<usize>, lists: bool) -> Edit {
+        let before = snapshot(store, &ids, lists);
+        Edit { ids, lists, before }
+    }
+
+    fn commit(self, store: &Store, backend: &mut dyn TaskStore, log: &OpLog, summary: String) {
+        persist(backend, store, &self.ids, self.lists);
+        log.append(
+            EntryKind::Do,
+            summary,
+            self.before,
+            snapshot(store, &self.ids, self.lists),
+        );
+    }
+}
+
+fn snapshot(store: &Store, ids: &[usize], lists: bool) -> Snapshot {
+    Snapshot {
+        tasks: store
+            .tasks
+            .iter()
+            .filter(|t| ids.contains(&t.id))
+            .cloned()
+            .collect(),
+        lists: lists.then(|| store.lists.clone()),
+    }
+}
+
+/// Saves the tasks with `ids`. Operations either touch a single task or
+/// change lists, moving tasks between them, which `Change::Lists` covers.
+fn persist(backend: &mut dyn TaskStore, store: &Store, ids: &[usize], lists: bool) {
+    if lists {
+        backend.save(store, Change::Lists);
+    } else {
+        for &id in ids {
+            backend.save(store, Change::Task(id));
+        }
+    }
+}
+
+/// Replaces the tasks in `from` with those in `to`, and the lists if `to`
+/// has them. Ids are never reused, so re-inserted tasks keep theirs.
+fn restore(store: &mut Store, from: &Snapshot, to: &Snapshot) {
+    store
+        .tasks
+        .retain(|t| !from.tasks.iter().any(|old| old.id == t.id));
+    store.tasks.extend(to.tasks.iter().cloned());
+    store.tasks.sort_by_key(|t| t.id);
+    if let Some(lists) = &to.lists {
+        store.lists = lists.clone();
+    }
+}
+
+/// `todo.json.log`: one JSON entry per line, only ever appended to. Undo and
+/// redo are entries too, so the log doubles as the history.
+struct OpLog {
+    path: PathBuf,
+}
+
+impl OpLog {
+    fn entries(&self) -> Vec<Entry> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(e) => fail(&format!("reading {}", self.path.display()), e),
+        };
+        BufReader::new(file)
+            .lines()
+            .enumerate()
+            .map(|(i, line)| {
+                let line =
+                    line.unwrap_or_else(|e| fail(&format!("reading {}", self.path.display()), e));
+                serde_json::from_str(&line).unwrap_or_else(|e| {
+                    fail(
+                        &format!("parsing line {} of {}", i + 1, self.path.display()),
+                        e,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Counts lines rather than parsing them, which is all a new entry needs.
+    fn next_seq(&self) -> usize {
+        match File::open(&self.path) {
+            Ok(file) => BufReader::new(file).lines().count() + 1,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 1,
+            Err(e) => fail(&format!("reading {}", self.path.display()), e),
+        }
+    }
+
+    fn append(&self, kind: EntryKind, summary: String, before: Snapshot, after: Snapshot) {
+        let entry = Entry {
+            seq: self.next_seq(),
+            at: Local::now(),
+            kind,
+            summary,
+            before,
+            after,
+        };
+        let line = serde_json::to_string(&entry).unwrap_or_else(|e| fail("serializing the log", e));
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .unwrap_or_else(|e| fail(&format!("writing {}", self.path.display()), e));
+    }
+
+    /// Replays the log into the entries `undo` and `redo` would act on next,
+    /// most recent last. A new command clears the redo stack.
+    fn stacks(entries: &[Entry]) -> (Vec<usize>, Vec<usize>) {
+        let (mut undo, mut redo) = (Vec::new(), Vec::new());
+        for entry in entries {
+            match entry.kind {
+                EntryKind::Do => {
+                    undo.push(entry.seq);
+                    redo.clear();
+                }
+                EntryKind::Undo { of } => {
+                    undo.retain(|&seq| seq != of);
+                    redo.push(of);
+                }
+                EntryKind::Redo { of } => {
+                    redo.retain(|&seq| seq != of);
+                    undo.push(of);
+                }
+            }
+        }
+        (undo, redo)
+    }
+}
+
+/// Turns a version 0 task list into a version 1 store. Ids used to be
+/// `len() + 1`, so removals could leave duplicates; later copies get fresh ids.
+fn migrate(mut tasks: Vec<Task>) -> Store {
+    let mut next_id = tasks.iter().map(|t| t.id).max().unwrap_or(0) + 1;
+    let mut seen = std::collections::HashSet::new();
+    for task in &mut tasks {
+        if !seen.insert(task.id) {
+            eprintln!(
+                "Task {} had a duplicate id; it is now {}.",
+                task.id, next_id
+            );
+            task.id = next_id;
+            next_id += 1;
+        }
+    }
+    Store {
+        version: 1,
+        next_id,
+        lists: Vec::new(),
+        tasks,
+    }
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Task store to use; defaults to $TODO_FILE, then the user data directory
+    #[arg(long, global = true)]
+    file: Option<PathBuf>,
+    /// Named list to add to or show; `list` shows every list when omitted
+    #[arg(short, long, global = true)]
+    list: Option<String>,
+    /// Storage backend; guessed from the file extension (.db, .sqlite) if omitted
+    #[arg(long, global = true, value_enum)]
+    backend: Option<Backend>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -22,72 +352,1087 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Add a new task
-    Add { description: String },
-    /// List all tasks
-    List,
+    Add {
+        description: String,
+        #[arg(short, long, value_enum)]
+        priority: Option<Priority>,
+        /// Due date: YYYY-MM-DD, today, tomorrow, a weekday or "in N days"
+        #[arg(short, long, value_parser = parse_due)]
+        due: Option<NaiveDate>,
+        /// Tag the task; repeat or separate with commas for several
+        #[arg(short, long = "tag", value_delimiter = ',')]
+        tags: Vec<String>,
+    },
+    /// List tasks, optionally filtered and sorted
+    List(ListArgs),
     /// Mark a task as done
     Done { id: usize },
     /// Remove a task
     Remove { id: usize },
+    /// Move a task to another list
+    Move { id: usize, to: String },
+    /// Show or manage named lists
+    Lists {
+        #[command(subcommand)]
+        action: Option<ListsAction>,
+    },
+    /// Revert the most recent change
+    Undo,
+    /// Re-apply the most recently undone change
+    Redo,
+    /// Show the operation log, newest last
+    History {
+        /// How many entries to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Copy every task into a new store, e.g. from JSON to SQLite
+    Migrate {
+        /// Store to create; it must not hold any tasks yet
+        dest: PathBuf,
+        /// Backend of the new store; guessed from its extension if omitted
+        #[arg(long, value_enum)]
+        to: Option<Backend>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ListsAction {
+    /// Create an empty list
+    Create { name: String },
+    /// Rename a list, keeping its tasks
+    Rename { from: String, to: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SortKey {
+    /// Earliest due date first; undated tasks last
+    Due,
+    /// Highest priority first
+    Priority,
+    /// Oldest first
+    Created,
+}
+
+#[derive(Args)]
+struct ListArgs {
+    /// Only tasks that are not done
+    #[arg(long, conflicts_with = "done")]
+    pending: bool,
+    /// Only completed tasks
+    #[arg(long)]
+    done: bool,
+    /// Only tasks with this tag
+    #[arg(short, long)]
+    tag: Option<String>,
+    /// Only tasks due before this date
+    #[arg(long, value_parser = parse_due)]
+    due_before: Option<NaiveDate>,
+    /// Only tasks with this priority
+    #[arg(short, long, value_enum)]
+    priority: Option<Priority>,
+    /// Only tasks whose description or tags contain this text
+    #[arg(short, long)]
+    search: Option<String>,
+    #[arg(long, value_enum)]
+    sort: Option<SortKey>,
+}
+
+impl ListArgs {
+    fn matches(&self, task: &Task) -> bool {
+        let search = self.search.as_ref().map(|text| text.to_lowercase());
+        (!self.pending || !task.done)
+            && (!self.done || task.done)
+            && self.tag.as_ref().is_none_or(|tag| task.tags.contains(tag))
+            && self
+                .due_before
+                .is_none_or(|date| task.due.is_some_and(|due| due < date))
+            && self.priority.is_none_or(|p| task.priority == Some(p))
+            && search.is_none_or(|text| {
+                task.description.to_lowercase().contains(&text)
+                    || task
+                        .tags
+                        .iter()
+                        .any(|tag| tag.to_lowercase().contains(&text))
+            })
+    }
+}
+
+fn is_overdue(task: &Task, today: NaiveDate) -> bool {
+    !task.done && task.due.is_some_and(|due| due < today)
+}
+
+/// One line of `list` output; `show_list` adds the task's list name.
+fn format_task(task: &Task, today: NaiveDate, show_list: bool) -> String {
+    let status = if task.done { "[Done]" } else { "[Pending]" };
+    let mut details = Vec::new();
+    if let Some(priority) = task.priority {
+        details.push(format!("priority: {:?}", priority).to_lowercase());
+    }
+    if let Some(due) = task.due {
+        details.push(format!("due: {}", due));
+    }
+    let mut line = format!("{} - {} {}", task.id, status, task.description);
+    if !details.is_empty() {
+        line += &format!(" ({})", details.join(", "));
+    }
+    for tag in &task.tags {
+        line += &format!(" #{}", tag);
+    }
+    if show_list {
+        line += &format!(" @{}", task.list);
+    }
+    if is_overdue(task, today) {
+        line += " ⚠️ overdue";
+    }
+    line
+}
+
+/// Parses a due date relative to today. Weekday names mean the next such
+/// day, never today.
+fn parse_due(input: &str) -> Result<NaiveDate, String> {
+    parse_due_from(input, Local::now().date_naive())
+}
+
+fn parse_due_from(input: &str, today: NaiveDate) -> Result<NaiveDate, String> {
+    let input = input.trim().to_lowercase();
+    match input.as_str() {
+        "today" => return Ok(today),
+        "tomorrow" => return Ok(today + Duration::days(1)),
+        "next week" => return Ok(today + Duration::weeks(1)),
+        _ => {}
+    }
+    if let Ok(weekday) = input.parse::<Weekday>() {
+        let ahead = (weekday.num_days_from_monday() as i64
+            - today.weekday().num_days_from_monday() as i64)
+            .rem_euclid(7);
+        return Ok(today + Duration::days(if ahead == 0 { 7 } else { ahead }));
+    }
+    if let Some(days) = input
+        .strip_prefix("in ")
+        .and_then(|rest| rest.strip_suffix(" days").or(rest.strip_suffix(" day")))
+    {
+        let days: i64 = days
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid number of days: {}", days))?;
+        return Duration::try_days(days)
+            .and_then(|delta| today.checked_add_signed(delta))
+            .ok_or_else(|| format!("{} days from today is out of range", days));
+    }
+    NaiveDate::parse_from_str(&input, "%Y-%m-%d").map_err(|_| {
+        format!(
+            "unrecognised date '{}'; use YYYY-MM-DD or e.g. tomorrow",
+            input
+        )
+    })
 }
 
 fn main() {
     let cli = Cli::parse();
-    let mut tasks = load_tasks();
+    let path = store_path(cli.file, cli.backend);
+    // Held until exit so concurrent invocations cannot interleave a
+    // load and a save.
+    let _lock = lock_store(&path);
+    let mut backend = open_store(&path, backend_for(&path, cli.backend));
+    let mut store = backend.load();
+    let log = OpLog {
+        path: sibling(&path, "log"),
+    };
+    if let Some(list) = cli.list.as_deref().filter(|list| !store.has_list(list)) {
+        fail(
+            "selecting the list",
+            format!(
+                "there is no list '{}'; create it with `lists create {}`",
+                list, list
+            ),
+        );
+    }
 
     match cli.command {
-        Commands::Add { description } => {
-            let id = tasks.len() + 1;
-            tasks.push(Task {
+        Commands::Add {
+            description,
+            priority,
+            due,
+            tags,
+        } => {
+            let id = store.next_id;
+            let edit = Edit::begin(&store, vec![id], false);
+            let summary = format!("add {} \"{}\"", id, description);
+            store.next_id += 1;
+            store.tasks.push(Task {
                 id,
                 description,
                 done: false,
+                priority,
+                due,
+                tags,
+                created: Some(Local::now()),
+                completed: None,
+                list: cli.list.unwrap_or_else(default_list),
             });
-            save_tasks(&tasks);
+            edit.commit(&store, &mut *backend, &log, summary);
             println!("✅ Task added!");
         }
-        Commands::List => {
-            for task in &tasks {
-                let status = if task.done { "[Done]" } else { "[Pending]" };
-                println!("{} - {} {}", task.id, status, task.description);
+        Commands::List(args) => {
+            let today = Local::now().date_naive();
+            let mut shown: Vec<&Task> = store
+                .tasks
+                .iter()
+                .filter(|t| cli.list.as_ref().is_none_or(|list| &t.list == list))
+                .filter(|t| args.matches(t))
+                .collect();
+            match args.sort {
+                Some(SortKey::Due) => shown.sort_by_key(|t| (t.due.is_none(), t.due)),
+                Some(SortKey::Priority) => shown.sort_by_key(|t| std::cmp::Reverse(t.priority)),
+                Some(SortKey::Created) => shown.sort_by_key(|t| t.created),
+                None => {}
+            }
+            let color = io::stdout().is_terminal();
+            for task in shown {
+                let line = format_task(task, today, cli.list.is_none());
+                if color && is_overdue(task, today) {
+                    println!("\x1b[31m{}\x1b[0m", line);
+                } else {
+                    println!("{}", line);
+                }
             }
         }
         Commands::Done { id } => {
-            if let Some(task) = tasks.iter_mut().find(|t| t.id == id) {
+            let edit = Edit::begin(&store, vec![id], false);
+            if let Some(task) = store.tasks.iter_mut().find(|t| t.id == id) {
                 task.done = true;
-                save_tasks(&tasks);
+                task.completed = Some(Local::now());
+                let summary = format!("done {} \"{}\"", id, task.description);
+                edit.commit(&store, &mut *backend, &log, summary);
                 println!("✅ Task marked as done!");
             } else {
                 println!("❌ Task not found!");
             }
         }
         Commands::Remove { id } => {
-            if tasks.iter().any(|t| t.id == id) {
-                tasks.retain(|t| t.id != id);
-                save_tasks(&tasks);
+            let edit = Edit::begin(&store, vec![id], false);
+            if let Some(task) = edit.before.tasks.first() {
+                let summary = format!("remove {} \"{}\"", id, task.description);
+                store.tasks.retain(|t| t.id != id);
+                edit.commit(&store, &mut *backend, &log, summary);
                 println!("🗑️ Task removed!");
             } else {
                 println!("❌ Task not found!");
             }
         }
+        Commands::Move { id, to } => {
+            let edit = Edit::begin(&store, vec![id], false);
+            if !store.has_list(&to) {
+                println!("❌ List not found!");
+            } else if let Some(task) = store.tasks.iter_mut().find(|t| t.id == id) {
+                let summary = format!("move {} from {} to {}", id, task.list, to);
+                task.list = to;
+                edit.commit(&store, &mut *backend, &log, summary);
+                println!("📦 Task moved!");
+            } else {
+                println!("❌ Task not found!");
+            }
+        }
+        Commands::Lists { action: None } => {
+            for list in &store.lists {
+                let tasks = store.tasks.iter().filter(|t| &t.list == list);
+                let pending = tasks.clone().filter(|t| !t.done).count();
+                println!("{} - {} pending, {} total", list, pending, tasks.count());
+            }
+        }
+        Commands::Lists {
+            action: Some(ListsAction::Create { name }),
+        } => {
+            if store.has_list(&name) {
+                println!("❌ List already exists!");
+            } else {
+                let edit = Edit::begin(&store, Vec::new(), true);
+                let summary = format!("create list {}", name);
+                store.lists.push(name);
+                edit.commit(&store, &mut *backend, &log, summary);
+                println!("✅ List created!");
+            }
+        }
+        Commands::Lists {
+            action: Some(ListsAction::Rename { from, to }),
+        } => {
+            if from == DEFAULT_LIST {
+                println!("❌ The default list cannot be renamed!");
+            } else if store.has_list(&to) {
+                println!("❌ List already exists!");
+            } else if store.has_list(&from) {
+                let ids = store
+                    .tasks
+                    .iter()
+                    .filter(|t| t.list == from)
+                    .map(|t| t.id)
+                    .collect();
+                let edit = Edit::begin(&store, ids, true);
+                for list in store.lists.iter_mut().filter(|list| **list == from) {
+                    *list = to.clone();
+                }
+                for task in store.tasks.iter_mut().filter(|t| t.list == from) {
+                    task.list = to.clone();
+                }
+                edit.commit(
+                    &store,
+                    &mut *backend,
+                    &log,
+                    format!("rename list {} to {}", from, to),
+                );
+                println!("✏️ List renamed!");
+            } else {
+                println!("❌ List not found!");
+            }
+        }
+        Commands::Undo => match undo(&mut store, &mut *backend, &log) {
+            Some(summary) => println!("↩️ Undid: {}", summary),
+            None => println!("❌ Nothing to undo!"),
+        },
+        Commands::Redo => match redo(&mut store, &mut *backend, &log) {
+            Some(summary) => println!("↪️ Redid: {}", summary),
+            None => println!("❌ Nothing to redo!"),
+        },
+        Commands::History { limit } => {
+            let entries = log.entries();
+            for entry in &entries[entries.len().saturating_sub(limit)..] {
+                let action = match entry.kind {
+                    EntryKind::Do => String::new(),
+                    EntryKind::Undo { of } => format!("undo #{}: ", of),
+                    EntryKind::Redo { of } => format!("redo #{}: ", of),
+                };
+                println!(
+                    "#{} {} {}{}",
+                    entry.seq,
+                    entry.at.format("%Y-%m-%d %H:%M"),
+                    action,
+                    entry.summary
+                );
+            }
+        }
+        Commands::Migrate { dest, to } => {
+            if fs::canonicalize(&dest)
+                .is_ok_and(|dest| fs::canonicalize(&path).is_ok_and(|p| p == dest))
+            {
+                fail("migrating", "the destination is the current store");
+            }
+            let _dest_lock = lock_store(&dest);
+            let mut target = open_store(&dest, backend_for(&dest, to));
+            let existing = target.load();
+            if !existing.tasks.is_empty() || existing.lists.len() > 1 {
+                fail(
+                    "migrating",
+                    format!("{} already holds tasks or lists", dest.display()),
+                );
+            }
+            target.save(&store, Change::All);
+            println!(
+                "🚚 Copied {} tasks to {}!",
+                store.tasks.len(),
+                dest.display()
+            );
+        }
     }
 }
 
-fn load_tasks() -> Vec<Task> {
-    if let Ok(content) = fs::read_to_string(FILE_PATH) {
-        serde_json::from_str(&content).unwrap_or_else(|_| Vec::new())
-    } else {
-        Vec::new()
+/// Reverts the most recent entry on the undo stack and returns its summary,
+/// or `None` if there is nothing to undo.
+fn undo(store: &mut Store, backend: &mut dyn TaskStore, log: &OpLog) -> Option<String> {
+    let entries = log.entries();
+    let (undo, _) = OpLog::stacks(&entries);
+    let seq = *undo.last()?;
+    let entry = &entries[seq - 1];
+    restore(store, &entry.after, &entry.before);
+    persist(
+        backend,
+        store,
+        &touched(entry),
+        entry.before.lists.is_some(),
+    );
+    log.append(
+        EntryKind::Undo { of: seq },
+        entry.summary.clone(),
+        entry.after.clone(),
+        entry.before.clone(),
+    );
+    Some(entry.summary.clone())
+}
+
+/// Re-applies the most recently undone entry and returns its summary, or
+/// `None` if there is nothing to redo.
+fn redo(store: &mut Store, backend: &mut dyn TaskStore, log: &OpLog) -> Option<String> {
+    let entries = log.entries();
+    let (_, redo) = OpLog::stacks(&entries);
+    let seq = *redo.last()?;
+    let entry = &entries[seq - 1];
+    restore(store, &entry.before, &entry.after);
+    persist(backend, store, &touched(entry), entry.after.lists.is_some());
+    log.append(
+        EntryKind::Redo { of: seq },
+        entry.summary.clone(),
+        entry.before.clone(),
+        entry.after.clone(),
+    );
+    Some(entry.summary.clone())
+}
+
+/// Ids of every task an entry added, changed or removed.
+fn touched(entry: &Entry) -> Vec<usize> {
+    let mut ids: Vec<usize> = entry
+        .before
+        .tasks
+        .iter()
+        .chain(&entry.after.tasks)
+        .map(|t| t.id)
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+fn empty_store() -> Store {
+    Store {
+        version: SCHEMA_VERSION,
+        next_id: 1,
+        lists: vec![default_list()],
+        tasks: Vec::new(),
     }
 }
 
-fn save_tasks(tasks: &Vec<Task>) {
-    let content = serde_json::to_string_pretty(tasks).expect("Failed to serialize tasks");
-    let mut file = OpenOptions::new()
-        .write(true)
+/// Resolves the store from `--file`, then `TODO_FILE`, then
+/// `$XDG_DATA_HOME/todo/todo.json` (or the platform's data directory).
+/// The default file is `todo.db` instead when `--backend sqlite` is given.
+/// Stores used to live in the working directory, so a store left there is
+/// pointed out when the default one does not exist yet.
+fn store_path(file: Option<PathBuf>, backend: Option<Backend>) -> PathBuf {
+    let name = match backend {
+        Some(Backend::Sqlite) => DB_FILE_NAME,
+        _ => FILE_NAME,
+    };
+    let explicit = file.or_else(|| std::env::var_os("TODO_FILE").map(PathBuf::from));
+    let path = explicit.unwrap_or_else(|| {
+        let path = dirs::data_dir()
+            .map(|dir| dir.join("todo").join(name))
+            .unwrap_or_else(|| PathBuf::from(name));
+        let local = Path::new(name);
+        if !path.exists() && local.exists() && path != local {
+            eprintln!(
+                "No store at {}, but ./{} exists; pass --file {} or set TODO_FILE to keep using it.",
+                path.display(),
+                name,
+                name
+            );
+        }
+        path
+    });
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .unwrap_or_else(|e| fail(&format!("creating {}", parent.display()), e));
+    }
+    path
+}
+
+/// Prints the error to stderr and exits with a failure status.
+fn fail(context: &str, e: impl Display) -> ! {
+    eprintln!("❌ Error {}: {}", context, e);
+    std::process::exit(1);
+}
+
+/// A path next to the store, e.g. `todo.json.bak`.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Takes an exclusive advisory lock on `todo.json.lock`, waiting for other
+/// invocations to finish. The lock is released when the file is dropped.
+fn lock_store(path: &Path) -> File {
+    let file = OpenOptions::new()
         .create(true)
-        .truncate(true)
-        .open(FILE_PATH)
-        .expect("Failed to open tasks file");
-    file.write_all(content.as_bytes()).expect("Failed to write to file");
+        .truncate(false)
+        .write(true)
+        .open(sibling(path, "lock"))
+        .unwrap_or_else(|e| fail("opening the lock file", e));
+    file.lock_exclusive()
+        .unwrap_or_else(|e| fail("locking the task store", e));
+    file
+}
+
+/// Uses `explicit` if given, otherwise SQLite for `.db`, `.sqlite` and
+/// `.sqlite3` files and JSON for everything else.
+fn backend_for(path: &Path, explicit: Option<Backend>) -> Backend {
+    explicit.unwrap_or_else(|| match path.extension().and_then(|ext| ext.to_str()) {
+        Some("db" | "sqlite" | "sqlite3") => Backend::Sqlite,
+        _ => Backend::Json,
+    })
+}
+
+fn open_store(path: &Path, backend: Backend) -> Box<dyn TaskStore> {
+    match backend {
+        Backend::Json => Box::new(JsonStore {
+            path: path.to_path_buf(),
+        }),
+        Backend::Sqlite => Box::new(SqliteStore::open(path)),
+    }
+}
+
+/// The whole store as one pretty-printed JSON document.
+struct JsonStore {
+    path: PathBuf,
+}
+
+impl TaskStore for JsonStore {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A missing file is an empty store; an unreadable one aborts so the
+    /// next save cannot overwrite it.
+    fn load_stored(&mut self) -> Store {
+        let path = self.path.as_path();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return empty_store(),
+            Err(e) => fail(&format!("reading {}", path.display()), e),
+        };
+        let parse_error = |e: serde_json::Error| -> ! {
+            fail(
+                &format!("parsing {}", path.display()),
+                format!(
+                    "{}. Nothing was changed; fix the file or restore {}.",
+                    e,
+                    sibling(path, "bak").display()
+                ),
+            )
+        };
+        let value: serde_json::Value =
+            serde_json::from_str(&content).unwrap_or_else(|e| parse_error(e));
+        // Version 0 was a bare array of tasks.
+        if value.is_array() {
+            return migrate(serde_json::from_value(value).unwrap_or_else(|e| parse_error(e)));
+        }
+        serde_json::from_value(value).unwrap_or_else(|e| parse_error(e))
+    }
+
+    /// Writes the store to a temporary file and renames it over `todo.json`,
+    /// so a crash leaves either the old or the new contents. The previous
+    /// version is kept as `todo.json.bak`.
+    fn save(&mut self, store: &Store, _change: Change) {
+        let path = self.path.as_path();
+        let content =
+            serde_json::to_string_pretty(store).unwrap_or_else(|e| fail("serializing tasks", e));
+        let temp_path = sibling(path, "tmp");
+        let mut file =
+            File::create(&temp_path).unwrap_or_else(|e| fail("creating the temp file", e));
+        file.write_all(content.as_bytes())
+            .and_then(|_| file.sync_all())
+            .unwrap_or_else(|e| fail("writing the temp file", e));
+        if fs::metadata(path).is_ok() {
+            fs::copy(path, sibling(path, "bak")).unwrap_or_else(|e| fail("writing the backup", e));
+        }
+        fs::rename(&temp_path, path)
+            .unwrap_or_else(|e| fail(&format!("replacing {}", path.display()), e));
+    }
+}
+
+/// Tasks as rows, so a change rewrites only the rows it touched. Tags are
+/// stored as a JSON array and dates as ISO 8601 text.
+struct SqliteStore {
+    path: PathBuf,
+    conn: Connection,
+}
+
+impl SqliteStore {
+    fn open(path: &Path) -> SqliteStore {
+        let conn = Connection::open(path)
+            .unwrap_or_else(|e| fail(&format!("opening {}", path.display()), e));
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value INTEGER NOT NULL);
+             CREATE TABLE IF NOT EXISTS lists (position INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE);
+             CREATE TABLE IF NOT EXISTS tasks (
+                 id INTEGER PRIMARY KEY,
+                 description TEXT NOT NULL,
+                 done INTEGER NOT NULL,
+                 priority TEXT,
+                 due TEXT,
+                 tags TEXT NOT NULL,
+                 created TEXT,
+                 completed TEXT,
+                 list TEXT NOT NULL
+             );",
+        )
+        .unwrap_or_else(|e| fail(&format!("creating tables in {}", path.display()), e));
+        SqliteStore {
+            path: path.to_path_buf(),
+            conn,
+        }
+    }
+
+    fn read(&self) -> rusqlite::Result<Option<Store>> {
+        let meta = |key: &str| -> rusqlite::Result<Option<i64>> {
+            self.conn
+                .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+                    row.get(0)
+                })
+                .optional()
+        };
+        let (Some(version), Some(next_id)) = (meta("version")?, meta("next_id")?) else {
+            return Ok(None);
+        };
+        let lists = self
+            .conn
+            .prepare("SELECT name FROM lists ORDER BY position")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        let mut query = self.conn.prepare(
+            "SELECT id, description, done, priority, due, tags, created, completed, list
+             FROM tasks ORDER BY id",
+        )?;
+        let rows = query.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, bool>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, Option<String>>(6)?,
+                row.get::<_, Option<String>>(7)?,
+                row.get::<_, String>(8)?,
+            ))
+        })?;
+        let mut tasks = Vec::new();
+        for row in rows {
+            let (id, description, done, priority, due, tags, created, completed, list) = row?;
+            let task = decode_task(
+                id,
+                description,
+                done,
+                priority,
+                due,
+                &tags,
+                created,
+                completed,
+                list,
+            )
+            .unwrap_or_else(|e| {
+                fail(
+                    &format!("reading task {} in {}", id, self.path.display()),
+                    e,
+                )
+            });
+            tasks.push(task);
+        }
+        Ok(Some(Store {
+            version: version as u32,
+            next_id: next_id as usize,
+            lists,
+            tasks,
+        }))
+    }
+
+    fn write(&mut self, store: &Store, change: Change) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        // The first write to a new database also records the initial lists,
+        // which a single-task change would otherwise leave out.
+        let meta_rows: i64 = tx.query_row("SELECT COUNT(*) FROM meta", [], |row| row.get(0))?;
+        if meta_rows == 0 {
+            write_lists(&tx, store)?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('version', ?1), ('next_id', ?2)",
+            params![store.version, store.next_id as i64],
+        )?;
+        match change {
+            Change::Task(id) => match store.tasks.iter().find(|t| t.id == id) {
+                Some(task) => write_task(&tx, task)?,
+                None => {
+                    tx.execute("DELETE FROM tasks WHERE id = ?1", [id as i64])?;
+                }
+            },
+            Change::Lists => {
+                write_lists(&tx, store)?;
+                // A rename moves tasks too; only those rows change.
+                let mut update =
+                    tx.prepare("UPDATE tasks SET list = ?2 WHERE id = ?1 AND list <> ?2")?;
+                for task in &store.tasks {
+                    update.execute(params![task.id as i64, task.list])?;
+                }
+            }
+            Change::All => {
+                write_lists(&tx, store)?;
+                tx.execute("DELETE FROM tasks", [])?;
+                for task in &store.tasks {
+                    write_task(&tx, task)?;
+                }
+            }
+        }
+        tx.commit()
+    }
+}
+
+impl TaskStore for SqliteStore {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A new database is an empty store.
+    fn load_stored(&mut self) -> Store {
+        self.read()
+            .unwrap_or_else(|e| fail(&format!("reading {}", self.path.display()), e))
+            .unwrap_or_else(empty_store)
+    }
+
+    /// Applies the change in one transaction, so a crash leaves either the
+    /// old or the new contents.
+    fn save(&mut self, store: &Store, change: Change) {
+        self.write(store, change)
+            .unwrap_or_else(|e| fail(&format!("writing {}", self.path.display()), e));
+    }
+}
+
+fn write_lists(tx: &Transaction, store: &Store) -> rusqlite::Result<()> {
+    tx.execute("DELETE FROM lists", [])?;
+    let mut insert = tx.prepare("INSERT INTO lists (position, name) VALUES (?1, ?2)")?;
+    for (position, name) in store.lists.iter().enumerate() {
+        insert.execute(params![position as i64, name])?;
+    }
+    Ok(())
+}
+
+fn write_task(tx: &Transaction, task: &Task) -> rusqlite::Result<()> {
+    let tags = serde_json::to_string(&task.tags).unwrap_or_else(|e| fail("serializing tags", e));
+    tx.execute(
+        "INSERT OR REPLACE INTO tasks
+         (id, description, done, priority, due, tags, created, completed, list)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            task.id as i64,
+            task.description,
+            task.done,
+            task.priority
+                .and_then(|p| p.to_possible_value())
+                .map(|p| p.get_name().to_string()),
+            task.due.map(|due| due.to_string()),
+            tags,
+            task.created.map(|t| t.to_rfc3339()),
+            task.completed.map(|t| t.to_rfc3339()),
+            task.list,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Turns the text columns of a `tasks` row back into a `Task`.
+#[allow(clippy::too_many_arguments)]
+fn decode_task(
+    id: i64,
+    description: String,
+    done: bool,
+    priority: Option<String>,
+    due: Option<String>,
+    tags: &str,
+    created: Option<String>,
+    completed: Option<String>,
+    list: String,
+) -> Result<Task, String> {
+    let timestamp = |text: Option<String>| {
+        text.map(|text| {
+            DateTime::parse_from_rfc3339(&text)
+                .map(|t| t.with_timezone(&Local))
+                .map_err(|e| format!("bad timestamp '{}': {}", text, e))
+        })
+        .transpose()
+    };
+    Ok(Task {
+        id: id as usize,
+        description,
+        done,
+        priority: priority
+            .map(|p| Priority::from_str(&p, false))
+            .transpose()?,
+        due: due
+            .map(|due| {
+                due.parse()
+                    .map_err(|e| format!("bad due date '{}': {}", due, e))
+            })
+            .transpose()?,
+        tags: serde_json::from_str(tags).map_err(|e| format!("bad tags '{}': {}", tags, e))?,
+        created: timestamp(created)?,
+        completed: timestamp(completed)?,
+        list,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn task(id: usize, description: &str, list: &str) -> Task {
+        Task {
+            id,
+            description: description.to_string(),
+            done: false,
+            priority: None,
+            due: None,
+            tags: Vec::new(),
+            created: None,
+            completed: None,
+            list: list.to_string(),
+        }
+    }
+
+    /// A path in the temp directory that no other test uses.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("todo-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Tasks have no `PartialEq`; their JSON form stands in for it.
+    fn json(value: &impl Serialize) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    /// Adds a task the way `todo add` does.
+    fn add(store: &mut Store, backend: &mut dyn TaskStore, log: &OpLog, description: &str) {
+        let id = store.next_id;
+        let edit = Edit::begin(store, vec![id], false);
+        store.next_id += 1;
+        store.tasks.push(task(id, description, DEFAULT_LIST));
+        edit.commit(store, backend, log, format!("add {}", description));
+    }
+
+    fn descriptions(store: &Store) -> Vec<&str> {
+        store.tasks.iter().map(|t| t.description.as_str()).collect()
+    }
+
+    fn entry(seq: usize, kind: EntryKind) -> Entry {
+        let empty = Snapshot {
+            tasks: Vec::new(),
+            lists: None,
+        };
+        Entry {
+            seq,
+            at: Local::now(),
+            kind,
+            summary: String::new(),
+            before: empty.clone(),
+            after: empty,
+        }
+    }
+
+    #[test]
+    fn stacks_replay_undo_and_redo() {
+        let mut entries = vec![
+            entry(1, EntryKind::Do),
+            entry(2, EntryKind::Do),
+            entry(3, EntryKind::Undo { of: 2 }),
+            entry(4, EntryKind::Undo { of: 1 }),
+            entry(5, EntryKind::Redo { of: 1 }),
+        ];
+        assert_eq!(OpLog::stacks(&entries), (vec![1], vec![2]));
+        // Undoing after a redo takes back the redone entry first.
+        entries.push(entry(6, EntryKind::Undo { of: 1 }));
+        assert_eq!(OpLog::stacks(&entries), (vec![], vec![2, 1]));
+        // A new command clears whatever could have been redone.
+        entries.push(entry(7, EntryKind::Do));
+        assert_eq!(OpLog::stacks(&entries), (vec![7], vec![]));
+    }
+
+    #[test]
+    fn undo_and_redo_replay_on_both_backends() {
+        for (name, backend) in [("undo.json", Backend::Json), ("undo.db", Backend::Sqlite)] {
+            let path = temp_path(name);
+            let log = OpLog {
+                path: temp_path(&format!("{}.log", name)),
+            };
+            let mut backend = open_store(&path, backend);
+            let mut store = backend.load();
+            add(&mut store, &mut *backend, &log, "a");
+            add(&mut store, &mut *backend, &log, "b");
+
+            assert_eq!(
+                undo(&mut store, &mut *backend, &log).as_deref(),
+                Some("add b")
+            );
+            assert_eq!(
+                undo(&mut store, &mut *backend, &log).as_deref(),
+                Some("add a")
+            );
+            assert_eq!(undo(&mut store, &mut *backend, &log), None);
+            assert_eq!(
+                redo(&mut store, &mut *backend, &log).as_deref(),
+                Some("add a")
+            );
+            assert_eq!(descriptions(&backend.load()), ["a"], "{}", name);
+            // Undo after redo reverts the redone command again.
+            assert_eq!(
+                undo(&mut store, &mut *backend, &log).as_deref(),
+                Some("add a")
+            );
+            assert!(backend.load().tasks.is_empty(), "{}", name);
+            assert_eq!(
+                redo(&mut store, &mut *backend, &log).as_deref(),
+                Some("add a")
+            );
+
+            // A new command clears the redo stack, so "b" is gone for good.
+            add(&mut store, &mut *backend, &log, "c");
+            assert_eq!(redo(&mut store, &mut *backend, &log), None);
+            let reloaded = backend.load();
+            assert_eq!(descriptions(&reloaded), ["a", "c"], "{}", name);
+            assert_eq!(json(&reloaded), json(&store), "{}", name);
+
+            let history: Vec<String> = log
+                .entries()
+                .iter()
+                .map(|e| format!("{} {:?} {}", e.seq, e.kind, e.summary))
+                .collect();
+            assert_eq!(
+                history,
+                [
+                    "1 Do add a",
+                    "2 Do add b",
+                    "3 Undo { of: 2 } add b",
+                    "4 Undo { of: 1 } add a",
+                    "5 Redo { of: 1 } add a",
+                    "6 Undo { of: 1 } add a",
+                    "7 Redo { of: 1 } add a",
+                    "8 Do add c",
+                ],
+                "{}",
+                name
+            );
+            drop(backend);
+            for path in [path.clone(), log.path, sibling(&path, "bak")] {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    #[test]
+    fn sqlite_rows_round_trip() {
+        let path = temp_path("rows.db");
+        let mut full = task(7, "file taxes", "home");
+        full.done = true;
+        full.priority = Some(Priority::High);
+        full.due = Some(date(2024, 4, 15));
+        full.tags = vec!["money".to_string(), "with \"quotes\"".to_string()];
+        full.created = Some(Local::now());
+        full.completed = Some(Local::now());
+        let store = Store {
+            version: SCHEMA_VERSION,
+            next_id: 9,
+            lists: vec![default_list(), "home".to_string()],
+            tasks: vec![task(3, "plain", DEFAULT_LIST), full],
+        };
+        SqliteStore::open(&path).save(&store, Change::All);
+        let loaded = SqliteStore::open(&path).load();
+        let _ = fs::remove_file(&path);
+        assert_eq!(json(&loaded), json(&store));
+    }
+
+    #[test]
+    fn decode_task_rejects_bad_columns() {
+        let decode = |priority: &str, due: &str, tags: &str, created: &str| {
+            decode_task(
+                1,
+                "x".to_string(),
+                false,
+                Some(priority.to_string()),
+                Some(due.to_string()),
+                tags,
+                Some(created.to_string()),
+                None,
+                default_list(),
+            )
+        };
+        let created = Local::now().to_rfc3339();
+        assert!(decode("low", "2024-01-02", "[]", &created).is_ok());
+        assert!(decode("urgent", "2024-01-02", "[]", &created).is_err());
+        assert!(decode("low", "2024-13-02", "[]", &created).is_err());
+        assert!(decode("low", "2024-01-02", "not json", &created).is_err());
+        assert!(decode("low", "2024-01-02", "[]", "yesterday").is_err());
+    }
+
+    #[test]
+    fn migrate_renumbers_duplicate_ids() {
+        let tasks = vec![
+            task(1, "a", DEFAULT_LIST),
+            task(2, "b", DEFAULT_LIST),
+            task(2, "c", "work"),
+            task(3, "d", DEFAULT_LIST),
+        ];
+        let mut store = migrate(tasks);
+        let ids: Vec<usize> = store.tasks.iter().map(|t| t.id).collect();
+        assert_eq!(ids, [1, 2, 4, 3]);
+        assert_eq!(store.next_id, 5);
+        assert_eq!(store.version, 1);
+        assert!(store.upgrade());
+        assert_eq!(store.version, SCHEMA_VERSION);
+        assert_eq!(store.lists, [DEFAULT_LIST, "work"]);
+    }
+
+    #[test]
+    fn version_0_files_are_upgraded_on_load() {
+        let path = temp_path("v0.json");
+        fs::write(
+            &path,
+            r#"[{"id": 1, "description": "a", "done": false},
+                {"id": 1, "description": "b", "done": true}]"#,
+        )
+        .unwrap();
+        let loaded = JsonStore { path: path.clone() }.load();
+        let saved = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(sibling(&path, "bak"));
+        assert_eq!(loaded.version, SCHEMA_VERSION);
+        assert_eq!(
+            loaded.tasks.iter().map(|t| t.id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        let saved: Store = serde_json::from_str(&saved).unwrap();
+        assert_eq!(json(&saved), json(&loaded));
+    }
+
+    #[test]
+    fn parse_due_understands_relative_dates() {
+        // A Wednesday.
+        let today = date(2024, 5, 15);
+        assert_eq!(parse_due_from("today", today), Ok(today));
+        assert_eq!(parse_due_from(" Tomorrow ", today), Ok(date(2024, 5, 16)));
+        assert_eq!(parse_due_from("next week", today), Ok(date(2024, 5, 22)));
+        assert_eq!(parse_due_from("in 1 day", today), Ok(date(2024, 5, 16)));
+        assert_eq!(parse_due_from("in 30 days", today), Ok(date(2024, 6, 14)));
+        assert_eq!(parse_due_from("2025-01-02", today), Ok(date(2025, 1, 2)));
+    }
+
+    #[test]
+    fn parse_due_weekdays_are_never_today() {
+        let today = date(2024, 5, 15);
+        assert_eq!(parse_due_from("friday", today), Ok(date(2024, 5, 17)));
+        assert_eq!(parse_due_from("mon", today), Ok(date(2024, 5, 20)));
+        assert_eq!(parse_due_from("Wednesday", today), Ok(date(2024, 5, 22)));
+    }
+
+    #[test]
+    fn parse_due_rejects_bad_input() {
+        let today = date(2024, 5, 15);
+        assert!(parse_due_from("in many days", today).is_err());
+        assert!(parse_due_from("in 99999999999 days", today).is_err());
+        assert!(parse_due_from(&format!("in {} days", i64::MAX), today).is_err());
+        assert!(parse_due_from("2024-02-30", today).is_err());
+        assert!(parse_due_from("someday", today).is_err());
+    }
 }